@@ -2,245 +2,3768 @@ extern crate yorickrt;
 extern crate hwtracer;
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, BufRead};
+use std::error;
+use std::fmt;
+use std::iter;
+use std::mem;
+use std::ops::Range;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufRead, Write};
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 use yorickrt::{MetaTracer, Location};
 use hwtracer::backends::TracerBuilder;
 
 type Program = Vec<(Instr, Location)>;
-type LabelMap = HashMap<String, usize>;
-type RawNumber = i32;
-type LabelName = String;
+// The type of all numbers in programs: stack values, operands and variables. This is the only
+// place the width is decided; everything else (parsing, arithmetic, overflow checks) follows it.
+pub type RawNumber = i64;
+pub type LabelName = String;
+pub type LabelId = usize;
+pub type StackName = String;
+pub type VarName = String;
+
+// The stack that is active when a program starts.
+const DEFAULT_STACK: &str = "default";
+
+// The number of cells in the memory used by `memload` and `memstore`.
+const MEMORY_SIZE: usize = 1 << 16;
+
+// Line comments start with this, unless `Interp::with_comment_prefix` says otherwise.
+pub const DEFAULT_COMMENT_PREFIX: &str = ";";
+
+// The random number generator's seed unless `Interp::set_seed` is called.
+pub const DEFAULT_SEED: u64 = 0;
+
+// Labels generated by the parser start with this character. User labels may not.
+const INTERNAL_LABEL_PREFIX: char = '$';
 
 pub struct Interp {
     program: Program,
-    labels: LabelMap,
-    stack: Stack,
+    labels: LabelInterner,
+    stack: Stack, // the active stack
+    stack_name: StackName, // the name of the active stack
+    stacks: HashMap<StackName, Stack>, // all other stacks which have been used
+    vars: HashMap<VarName, StackVal>,
+    memory: Vec<RawNumber>, // the cells stored to so far. Every cell from the end of this on is 0
+    calls: Vec<Frame>, // the active `call`s, innermost last
+    // Copies of the active stack saved by `checkpoint`, with the stack's name, latest last.
+    checkpoints: Vec<(StackName, Stack)>,
+    handlers: Vec<Handler>, // the active `try`s, innermost last
+    max_call_depth: Option<usize>, // if set, the most `call`s that may be active at once
+    steps: u64, // how many instructions have been executed since the start (or a `reset`)
+    label_lookups: u64, // how many jumps have looked their label up, likewise
+    depths: Vec<u64>, // the stack depth histogram, likewise. See `ExecStats::depths`
+    max_depth: usize, // the deepest the active stack has been, likewise
+    max_steps: Option<u64>, // if set, the most instructions that may be executed
+    safe: bool, // whether instructions with effects beyond the interpreter are forbidden
     pc: usize,
+    entry: usize, // where execution starts, and `reset` returns to
+    trace: Option<Box<dyn Write>>, // if set, a line is written here for each executed instruction
+    trace_jumps_only: bool, // whether the trace is limited to instructions that may jump
+    debug: bool, // whether debugging instructions (e.g. `expectstack`) are enabled
+    input: Box<dyn BufRead>, // where `read` gets its input from
+    rng: Rng, // the source of randomness for `shuffle`
+    seed: u64, // what `rng` was seeded with, so that `reset` can restart its sequence
+    spans: Vec<Span>, // where each instruction came from in the source, if it was parsed
 }
 
 impl Interp {
+    // Parse the program in file `filename`, or stdin if it is `-`. Errors are fatal.
     pub fn new(filename: &str) -> Self {
-        let (program, labels) = Self::parse(filename);
+        Self::with_comment_prefix(filename, DEFAULT_COMMENT_PREFIX)
+    }
+
+    // Like `new`, but line comments start with `prefix` (e.g. `#` or `//`) instead of the
+    // default. An empty prefix disables line comments.
+    pub fn with_comment_prefix(filename: &str, prefix: &str) -> Self {
+        let rv = if filename == "-" {
+            Self::from_reader_with_comment_prefix(io::stdin().lock(), prefix)
+        } else {
+            match File::open(filename) {
+                Ok(fh) => Self::from_reader_with_comment_prefix(BufReader::new(fh), prefix),
+                Err(_) => fatal(&format!("Failed to open input file: {}", filename)),
+            }
+        };
+        match rv {
+            Ok(interp) => interp,
+            Err(e) => fatal(&e.to_string()),
+        }
+    }
+
+    // Parse a program from `reader`, e.g. a string (as a byte slice) or a network stream.
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, InterpError> {
+        Self::from_reader_with_comment_prefix(reader, DEFAULT_COMMENT_PREFIX)
+    }
+
+    // Like `from_reader`, with the comment prefix of `with_comment_prefix`.
+    pub fn from_reader_with_comment_prefix<R: BufRead>(
+        reader: R,
+        prefix: &str,
+    ) -> Result<Self, InterpError> {
+        let (program, labels, spans) =
+            Self::parse(reader, prefix, false, false).map_err(|mut errors| errors.remove(0))?;
+        let mut interp = Self::from_program(program, labels);
+        interp.spans = spans;
+        Ok(interp)
+    }
+
+    // Like `from_reader_with_comment_prefix`, but for programs that aren't trusted: the program
+    // may not touch the filesystem (so `.data` is a parse error), and the interpreter is put in
+    // safe mode (see `set_safe_mode`).
+    pub fn from_reader_safe<R: BufRead>(reader: R, prefix: &str) -> Result<Self, InterpError> {
+        let (program, labels, spans) =
+            Self::parse(reader, prefix, false, true).map_err(|mut errors| errors.remove(0))?;
+        let mut interp = Self::from_program(program, labels);
+        interp.spans = spans;
+        interp.set_safe_mode(true);
+        Ok(interp)
+    }
+
+    // Like `from_reader_with_comment_prefix`, but rather than stopping at the first error, skip
+    // lines with errors and carry on, returning every error found. This suits linting, where
+    // seeing all the problems at once is more useful than a program to run. If `safe`, the
+    // program is parsed as by `from_reader_safe`.
+    pub fn from_reader_recovering<R: BufRead>(
+        reader: R,
+        prefix: &str,
+        safe: bool,
+    ) -> Result<Self, Vec<InterpError>> {
+        let (program, labels, spans) = Self::parse(reader, prefix, true, safe)?;
+        let mut interp = Self::from_program(program, labels);
+        interp.spans = spans;
+        interp.set_safe_mode(safe);
+        Ok(interp)
+    }
+
+    // Build an interpreter for an already constructed program, bypassing the parser. `labels`
+    // holds the labels that jumps in `program` refer to, and the index each label points at.
+    pub fn load_program(program: Vec<Instr>, labels: LabelInterner) -> Self {
+        let program = program.into_iter().map(|instr| (instr, Location::new())).collect();
+        Self::from_program(program, labels)
+    }
+
+    // The reverse of `load_program`: give up the interpreter, returning its program and labels.
+    // The program is unlinked (see `link`) on the way out, so that a transformed version of it
+    // can't jump to an address cached for the original.
+    pub fn into_program(self) -> (Vec<Instr>, LabelInterner) {
+        let program = self
+            .program
+            .into_iter()
+            .map(|(mut instr, _)| {
+                for target in instr.jump_targets_mut() {
+                    target.addr = None;
+                }
+                instr
+            })
+            .collect();
+        (program, self.labels)
+    }
+
+    fn from_program(program: Program, labels: LabelInterner) -> Self {
         Self {
             program: program,
             labels: labels,
             stack: Stack::new(),
+            stack_name: DEFAULT_STACK.to_owned(),
+            stacks: HashMap::new(),
+            vars: HashMap::new(),
+            memory: Vec::new(),
+            calls: Vec::new(),
+            checkpoints: Vec::new(),
+            handlers: Vec::new(),
+            max_call_depth: None,
+            steps: 0,
+            label_lookups: 0,
+            depths: Vec::new(),
+            max_depth: 0,
+            max_steps: None,
+            safe: false,
             pc: 0,
+            entry: 0,
+            trace: None,
+            trace_jumps_only: false,
+            debug: false,
+            input: Box::new(BufReader::new(io::stdin())),
+            spans: Vec::new(),
+            rng: Rng::new(DEFAULT_SEED),
+            seed: DEFAULT_SEED,
         }
     }
 
-    fn parse(filename: &str) -> (Program, LabelMap) {
-        // Get ready to iterate over the source program
-        let fh = File::open(filename);
-        if fh.is_err() {
-            fatal(&format!("Failed to open input file: {}", filename));
+    // Write an execution trace to `out`. Each line is the pc, opcode and stack depth prior to
+    // executing the instruction.
+    pub fn set_trace(&mut self, out: Box<dyn Write>) {
+        self.trace = Some(out);
+    }
+
+    // Limit the trace to instructions which may transfer control (jumps, `call` and `ret`), to
+    // show the control flow of a run. Each line is then the instruction's address, its opcode,
+    // `->` and the address execution continued at, whether or not a jump was taken.
+    pub fn set_trace_jumps_only(&mut self, jumps_only: bool) {
+        self.trace_jumps_only = jumps_only;
+    }
+
+    // Make the program read its input from `input` rather than stdin.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = input;
+    }
+
+    // The address of the next instruction to be executed.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    // The next instruction to be executed, or None if the program has ended.
+    pub fn next_instr(&self) -> Option<&Instr> {
+        self.program.get(self.pc).map(|&(ref instr, _)| instr)
+    }
+
+    // The program's labels, e.g. to name jump targets with `Instr::display`.
+    pub fn labels(&self) -> &LabelInterner {
+        &self.labels
+    }
+
+    // Where in the source the instruction at `addr` came from. None if the program wasn't parsed
+    // from source, or `addr` is out of range.
+    pub fn span(&self, addr: usize) -> Option<&Span> {
+        self.spans.get(addr)
+    }
+
+    // The names of the labels that refer to the instruction at `addr`, in the order they were
+    // first seen. Labels generated for structured control flow are included.
+    pub fn labels_at(&self, addr: usize) -> Vec<&str> {
+        self.labels.names_at(addr)
+    }
+
+    // Every defined label's name, grouped by the address it refers to.
+    pub fn labels_by_addr(&self) -> HashMap<usize, Vec<&str>> {
+        let mut rv: HashMap<usize, Vec<&str>> = HashMap::new();
+        for (id, name) in self.labels.names.iter().enumerate() {
+            if let Some(addr) = self.labels.address(id) {
+                rv.entry(addr).or_insert_with(Vec::new).push(name);
+            }
         }
-        let fh = fh.unwrap();
-        let reader = BufReader::new(fh);
+        rv
+    }
 
-        let mut program = Program::new();
-        let mut labels = LabelMap::new();
-        for line in reader.lines() {
-            match Self::parse_line(line.unwrap()) {
-                ParsedLine::Instr(instr) => program.push((instr, Location::new())),
-                ParsedLine::Label(label) => {
-                    if labels.insert(label, program.len()).is_some() {
-                        fatal("parse error: duplicate label");
-                    }
-                }
+    // The values on the active stack, bottom first.
+    pub fn stack(&self) -> &[StackVal] {
+        self.stack.contents()
+    }
+
+    // Start execution at label `name`, rather than the first instruction. This also applies to
+    // later `reset`s. Fails if the label isn't defined.
+    pub fn set_entry(&mut self, name: &str) -> Result<(), InterpError> {
+        match self.labels.lookup(name).and_then(|id| self.labels.address(id)) {
+            Some(addr) => {
+                self.entry = addr;
+                self.pc = addr;
+                Ok(())
             }
+            None => Err(InterpError::UndefinedLabel(name.to_owned())),
         }
-        (program, labels)
     }
 
-    fn parse_number<'a>(s: &'a str) -> RawNumber {
-        let num = s.parse::<RawNumber>();
-        if num.is_err() {
-            fatal("parse error: unparsed number");
+    // Enable or disable debugging instructions. When disabled, they do nothing.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    // Limit how deeply `call`s may nest, so that runaway recursion is an error rather than
+    // exhausting memory. None (the default) means no limit.
+    pub fn set_max_call_depth(&mut self, depth: Option<usize>) {
+        self.max_call_depth = depth;
+    }
+
+    // Counts of what the program has done since it started (or the last `reset`).
+    pub fn stats(&self) -> ExecStats {
+        ExecStats {
+            instructions: self.steps,
+            label_lookups: self.label_lookups,
+            max_depth: self.max_depth,
+            depths: self.depths.clone(),
         }
-        num.unwrap()
     }
 
-    fn parse_line(line: String) -> ParsedLine {
-        let line = line.trim();
-        let mut operands = line.split(" ").map(|x| x.trim());
+    // Limit how many instructions may be executed, e.g. to stop an untrusted program looping
+    // forever. The count starts again from zero on `reset`. None (the default) means no limit.
+    // While a limit is set, `sleep` fails, since it could otherwise stall for as long as it
+    // likes in a single step.
+    pub fn set_max_steps(&mut self, steps: Option<u64>) {
+        self.max_steps = steps;
+    }
+
+    // In safe mode, instructions whose effects reach beyond the interpreter's own state and
+    // output fail with `RuntimeError::OperationNotPermitted`. These are `exit`, which controls
+    // the process's exit code, and `sleep`, which could hold up a host for longer than the
+    // program's step limit suggests. Use `from_reader_safe` to also forbid the parser from
+    // accessing the filesystem.
+    pub fn set_safe_mode(&mut self, safe: bool) {
+        self.safe = safe;
+    }
 
-        let rv = {
-            let mut next_operand = || match operands.next() {
-                Some(s) => s.trim(),
-                None => {
-                    fatal("parse error: too few arguments");
+    // Seed the random number generator. A given seed always produces the same sequence, on any
+    // platform. Until this is called, `DEFAULT_SEED` is used.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+        self.seed = seed;
+    }
+
+    // Push a value on to the active stack, e.g. to pass arguments to a program before it runs.
+    pub fn push(&mut self, val: StackVal) {
+        self.stack.push(val);
+    }
+
+    // Return to the state the program started in, so that it can be run again without
+    // re-parsing it. All stacks, variables, memory, active calls and `try`s, and checkpoints
+    // are discarded, and the random number generator starts again from its seed, so each run
+    // shuffles the same way. The trace and input are kept.
+    pub fn reset(&mut self) {
+        self.stack = Stack::new();
+        self.stack_name = DEFAULT_STACK.to_owned();
+        self.stacks.clear();
+        self.vars.clear();
+        self.memory.clear();
+        self.calls.clear();
+        self.checkpoints.clear();
+        self.handlers.clear();
+        self.steps = 0;
+        self.label_lookups = 0;
+        self.depths.clear();
+        self.max_depth = 0;
+        self.rng = Rng::new(self.seed);
+        self.pc = self.entry;
+    }
+
+    // Capture the execution state (stacks, variables, memory, active calls and `try`s,
+    // checkpoints, the random number generator and pc) so that it can be returned to later with
+    // `restore`.
+    pub fn snapshot(&self) -> State {
+        State {
+            stack: self.stack.clone(),
+            stack_name: self.stack_name.clone(),
+            stacks: self.stacks.clone(),
+            vars: self.vars.clone(),
+            memory: self.memory.clone(),
+            calls: self.calls.clone(),
+            checkpoints: self.checkpoints.clone(),
+            handlers: self.handlers.clone(),
+            rng: self.rng.clone(),
+            pc: self.pc,
+        }
+    }
+
+    // Return to a state previously captured with `snapshot`.
+    pub fn restore(&mut self, state: State) {
+        self.stack = state.stack;
+        self.stack_name = state.stack_name;
+        self.stacks = state.stacks;
+        self.vars = state.vars;
+        self.memory = state.memory;
+        self.calls = state.calls;
+        self.checkpoints = state.checkpoints;
+        self.handlers = state.handlers;
+        self.rng = state.rng;
+        self.pc = state.pc;
+    }
+
+    // Parse a program. If `recover` is false, parsing stops at the first error. Otherwise, a
+    // line with an error is skipped and parsing continues, so that every error is reported. If
+    // `safe` is true, directives that access the filesystem are errors.
+    fn parse<R: BufRead>(
+        reader: R,
+        comment_prefix: &str,
+        recover: bool,
+        safe: bool,
+    ) -> Result<(Program, LabelInterner, Vec<Span>), Vec<InterpError>> {
+        let mut state = ParseState {
+            program: Program::new(),
+            labels: LabelInterner::new(),
+            spans: Vec::new(),
+            blocks: Vec::new(),
+            next_block_id: 0,
+            data: HashMap::new(),
+            safe: safe,
+        };
+        let mut errors = Vec::new();
+        let mut in_comment = false;
+        let mut comment_line = 0; // where the open block comment, if any, started
+        let mut line_num = 0;
+        for line in reader.lines() {
+            line_num += 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    errors.push(InterpError::InvalidEncoding { line: line_num });
+                    if !recover {
+                        return Err(errors);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    errors.push(InterpError::Io(e));
+                    return Err(errors);
                 }
             };
+            let was_in_comment = in_comment;
+            let line = Self::strip_comments(&line, comment_prefix, &mut in_comment);
+            if in_comment && !was_in_comment {
+                comment_line = line_num;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Err(msg) = Self::parse_source_line(&mut state, &line, line_num) {
+                errors.push(InterpError::Parse { line: line_num, msg: msg });
+                if !recover {
+                    return Err(errors);
+                }
+            }
+        }
+        if in_comment {
+            let msg = "unterminated block comment".to_owned();
+            errors.push(InterpError::Parse { line: comment_line, msg: msg });
+        }
+        if let Some(&(_, opened)) = state.blocks.last() {
+            let msg = "unterminated block".to_owned();
+            errors.push(InterpError::Parse { line: opened, msg: msg });
+        }
+        if errors.is_empty() {
+            Ok((state.program, state.labels, state.spans))
+        } else {
+            Err(errors)
+        }
+    }
 
-            let opcode = next_operand();
-            let rv = match opcode {
-                "add" => ParsedLine::Instr(Instr::Add),
-                "sub" => ParsedLine::Instr(Instr::Sub),
-                "print" => ParsedLine::Instr(Instr::Print),
-                "pop" => ParsedLine::Instr(Instr::Pop),
-                "dup" => ParsedLine::Instr(Instr::Dup),
-                "je" => {
-                    let cmp_val = Self::parse_number(next_operand());
-                    let target = next_operand();
-                    ParsedLine::Instr(Instr::JumpEqual(cmp_val, String::from(target)))
-                }
-                "jne" => {
-                    let cmp_val = Self::parse_number(next_operand());
-                    let target = next_operand();
-                    ParsedLine::Instr(Instr::JumpNotEqual(cmp_val, String::from(target)))
-                }
-                "push" => {
-                    let val = Self::parse_number(next_operand());
-                    ParsedLine::Instr(Instr::Push(StackVal::Number(val)))
+    // Parse line `line_num` of the source, whose comments have been removed, adding what it
+    // contains to `state`.
+    fn parse_source_line(
+        state: &mut ParseState,
+        line: &str,
+        line_num: usize,
+    ) -> Result<(), String> {
+        let &mut ParseState {
+            ref mut program,
+            ref mut labels,
+            ref mut spans,
+            ref mut blocks,
+            ref mut next_block_id,
+            ref mut data,
+            safe,
+        } = state;
+        let (parsed, cols) = Self::parse_line(line, labels)?;
+        match parsed {
+            ParsedLine::Instr(instr) => program.push((instr, Location::new())),
+            ParsedLine::Label(label) => Self::define_label(labels, &label, program.len())?,
+            ParsedLine::Data(_, _) if safe => {
+                return Err("`.data` is not permitted in safe mode".to_owned())
+            }
+            ParsedLine::Data(name, path) => {
+                if data.contains_key(&name) {
+                    return Err(format!("duplicate data '{}'", name));
                 }
-                _ => {
-                    if opcode.ends_with(":") {
-                        // XXX in a real interpreter you would resolve the labels to addresses
-                        // ahead of time so that: a) a bad label is compile-time detected, and b)
-                        // you don't have to repeatedly look them up.
-                        ParsedLine::Label(opcode[..opcode.len() - 1].to_owned())
-                    } else {
-                        fatal("parse error: unknown opcode");
-                    }
+                let vals = Self::load_data(&path)?;
+                data.insert(name, vals);
+            }
+            ParsedLine::PushData(name) => match data.get(&name) {
+                Some(vals) => {
+                    let instr = Instr::PushData(name.clone(), vals.clone());
+                    program.push((instr, Location::new()));
                 }
-            };
-            rv
-        };
-        if operands.next().is_some() {
-            fatal("parse error: too many operands");
+                None => return Err(format!("undefined data '{}'", name)),
+            },
+            ParsedLine::If => {
+                let id = *next_block_id;
+                *next_block_id += 1;
+                let target = labels.target(&Self::block_label("if", id, "else"));
+                let instr = Instr::JumpEqual(0, target);
+                program.push((instr, Location::new()));
+                blocks.push((Block::If(id), line_num));
+            }
+            ParsedLine::Else => match blocks.pop() {
+                Some((Block::If(id), opened)) => {
+                    let target = labels.target(&Self::block_label("if", id, "end"));
+                    let instr = Instr::Jump(target);
+                    program.push((instr, Location::new()));
+                    let addr = program.len();
+                    let label = Self::block_label("if", id, "else");
+                    Self::define_label(labels, &label, addr)?;
+                    blocks.push((Block::Else(id), opened));
+                }
+                _ => return Err("else without if".to_owned()),
+            },
+            ParsedLine::EndIf => match blocks.pop() {
+                Some((Block::If(id), _)) => {
+                    // No else branch: a false condition skips straight to the end.
+                    let addr = program.len();
+                    let label = Self::block_label("if", id, "else");
+                    Self::define_label(labels, &label, addr)?;
+                    let label = Self::block_label("if", id, "end");
+                    Self::define_label(labels, &label, addr)?;
+                }
+                Some((Block::Else(id), _)) => {
+                    let addr = program.len();
+                    let label = Self::block_label("if", id, "end");
+                    Self::define_label(labels, &label, addr)?;
+                }
+                _ => return Err("endif without if".to_owned()),
+            },
+            ParsedLine::While => {
+                let id = *next_block_id;
+                *next_block_id += 1;
+                let addr = program.len();
+                let label = Self::block_label("while", id, "top");
+                Self::define_label(labels, &label, addr)?;
+                let target = labels.target(&Self::block_label("while", id, "end"));
+                let instr = Instr::JumpEqual(0, target);
+                program.push((instr, Location::new()));
+                blocks.push((Block::While(id), line_num));
+            }
+            ParsedLine::EndWhile => match blocks.pop() {
+                Some((Block::While(id), _)) => {
+                    let target = labels.target(&Self::block_label("while", id, "top"));
+                    let instr = Instr::Jump(target);
+                    program.push((instr, Location::new()));
+                    let end = Self::block_label("while", id, "end");
+                    Self::define_label(labels, &end, program.len())?;
+                }
+                _ => return Err("endwhile without while".to_owned()),
+            },
+            ParsedLine::Repeat(var, count) => {
+                let id = *next_block_id;
+                *next_block_id += 1;
+                program.push((Instr::Push(StackVal::Number(count)), Location::new()));
+                program.push((Instr::Store(var.clone()), Location::new()));
+                let label = Self::block_label("repeat", id, "top");
+                Self::define_label(labels, &label, program.len())?;
+                program.push((Instr::Load(var.clone()), Location::new()));
+                let target = labels.target(&Self::block_label("repeat", id, "end"));
+                program.push((Instr::JumpEqual(0, target), Location::new()));
+                program.push((Instr::DecVar(var), Location::new()));
+                blocks.push((Block::Repeat(id), line_num));
+            }
+            ParsedLine::EndRepeat => match blocks.pop() {
+                Some((Block::Repeat(id), _)) => {
+                    let target = labels.target(&Self::block_label("repeat", id, "top"));
+                    program.push((Instr::Jump(target), Location::new()));
+                    let end = Self::block_label("repeat", id, "end");
+                    Self::define_label(labels, &end, program.len())?;
+                }
+                _ => return Err("endrepeat without repeat".to_owned()),
+            },
+        }
+        // Instructions generated for structured control flow get the span of the line which
+        // caused them.
+        let span = Span { line: line_num, start: cols.start, end: cols.end };
+        spans.resize(program.len(), span);
+        Ok(())
+    }
+
+    // Remove comments from `line`: everything from `prefix` to the end of the line, and
+    // `/* ... */` comments, which are blanked out with spaces so that the byte columns of what
+    // follows are unchanged. `in_comment` says whether the line starts inside a block comment,
+    // and is updated to say whether it ends inside one.
+    // Block comments don't nest: a comment ends at the first `*/`, whatever `/*`s precede it.
+    fn strip_comments(line: &str, prefix: &str, in_comment: &mut bool) -> String {
+        let mut rv = String::new();
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let next = chars.peek().map(|&(_, c)| c);
+            if *in_comment {
+                if c == '*' && next == Some('/') {
+                    chars.next();
+                    *in_comment = false;
+                    rv.push_str("  ");
+                } else {
+                    rv.extend(iter::repeat(' ').take(c.len_utf8()));
+                }
+            } else if in_string {
+                // Comment delimiters have no meaning inside string literals.
+                rv.push(c);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
+                }
+            } else if c == '/' && next == Some('*') {
+                chars.next();
+                *in_comment = true;
+                rv.push_str("  ");
+            } else if !prefix.is_empty() && line[i..].starts_with(prefix) {
+                break;
+            } else {
+                in_string = c == '"';
+                rv.push(c);
+            }
         }
         rv
     }
 
-    // main interpreter loop
-    pub fn run(&mut self) {
-        let tracer = TracerBuilder::new().build().unwrap();
-        let mt = MetaTracer::new(tracer);
-        loop {
-            let (instr, loc) = match self.program.get(self.pc) {
-                None => break, // end of program.
-                Some(tup) => tup,
-            };
-            mt.control_point(loc);
+    fn define_label(labels: &mut LabelInterner, label: &str, addr: usize) -> Result<(), String> {
+        if !labels.define(label, addr) {
+            return Err(format!("duplicate label '{}'", label));
+        }
+        Ok(())
+    }
 
-            match instr {
-                &Instr::Push(ref val) => {
-                    self.stack.push(val.clone());
-                    self.pc += 1;
+    // Name of an internal label generated for structured control flow.
+    fn block_label(kind: &str, id: usize, part: &str) -> LabelName {
+        format!("{}{}{}.{}", INTERNAL_LABEL_PREFIX, kind, id, part)
+    }
+
+    fn parse_number<'a>(s: &'a str) -> Result<RawNumber, String> {
+        // Single underscores may separate digits for readability, e.g. `1_000_000`.
+        let digits = s.trim_start_matches(|c| c == '-' || c == '+');
+        if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+            return Err("misplaced underscore in number".to_owned());
+        }
+        let num = s.replace('_', "").parse::<RawNumber>();
+        if num.is_err() {
+            return Err(format!("unparsed number '{}'", s));
+        }
+        Ok(num.unwrap())
+    }
+
+    // Parse a float literal. These always contain a decimal point, e.g. `1.5` or `2.0e10`.
+    fn parse_float(s: &str) -> Result<f64, String> {
+        s.parse::<f64>().map_err(|_| format!("unparsed float '{}'", s))
+    }
+
+    // Read the whitespace separated numbers in the file at `path`, for a `.data` directive.
+    fn load_data(path: &str) -> Result<Vec<RawNumber>, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return Err(format!("failed to read data file '{}': {}", path, e)),
+        };
+        text.split_whitespace()
+            .map(|tok| {
+                tok.parse::<RawNumber>()
+                    .map_err(|_| format!("data file '{}': '{}' is not a number", path, tok))
+            })
+            .collect()
+    }
+
+    // Parse a double quoted string literal. The escapes `\"`, `\\`, `\n` and `\t` are
+    // recognised.
+    fn parse_string(s: &str) -> Result<String, String> {
+        let mut rv = String::new();
+        let mut chars = s[1..s.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                rv.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('"') => rv.push('"'),
+                Some('\\') => rv.push('\\'),
+                Some('n') => rv.push('\n'),
+                Some('t') => rv.push('\t'),
+                Some(c) => return Err(format!("unknown escape '\\{}'", c)),
+                None => return Err("unterminated string".to_owned()),
+            }
+        }
+        Ok(rv)
+    }
+
+    // Parse one line of source, returning what it contains and the byte columns of its opcode.
+    fn parse_line(
+        line: &str,
+        labels: &mut LabelInterner,
+    ) -> Result<(ParsedLine, Range<usize>), String> {
+        let mut operands = Operands::new(line.trim())?;
+        let rv = Self::parse_operands(line, &mut operands, labels)?;
+        operands.finish()?;
+        Ok(rv)
+    }
+
+    // A jump target referring to label `name`, written in the program. Labels reserved for the
+    // parser (see `INTERNAL_LABEL_PREFIX`) can't be named, so that a jump can't land inside a
+    // block.
+    fn user_target(labels: &mut LabelInterner, name: &str) -> Result<JumpTarget, String> {
+        if name.starts_with(INTERNAL_LABEL_PREFIX) {
+            return Err("labels starting with '$' are reserved".to_owned());
+        }
+        Ok(labels.target(name))
+    }
+
+    // Parse the next line's worth of `operands`, which come from `line`, leaving any that aren't
+    // needed. Returns the parsed line and the columns of its opcode within `line`.
+    fn parse_operands(
+        line: &str,
+        operands: &mut Operands,
+        labels: &mut LabelInterner,
+    ) -> Result<(ParsedLine, Range<usize>), String> {
+        let opcode = operands.next()?;
+        let start = opcode.as_ptr() as usize - line.as_ptr() as usize;
+        let cols = start..start + opcode.len();
+        let rv = match opcode {
+            "add" => ParsedLine::Instr(Instr::Add),
+            "sub" => ParsedLine::Instr(Instr::Sub),
+            "rsub" => ParsedLine::Instr(Instr::RSub),
+            "sqrt" => ParsedLine::Instr(Instr::Sqrt),
+            "half" => ParsedLine::Instr(Instr::Half),
+            "iseven" => ParsedLine::Instr(Instr::IsEven),
+            "log2" => ParsedLine::Instr(Instr::Log2),
+            "toint" => ParsedLine::Instr(Instr::ToInt),
+            "tofloat" => ParsedLine::Instr(Instr::ToFloat),
+            "divmod" => ParsedLine::Instr(Instr::DivMod),
+            "digits" => ParsedLine::Instr(Instr::Digits),
+            "sum" => ParsedLine::Instr(Instr::Sum),
+            "product" => ParsedLine::Instr(Instr::Product),
+            "len" => ParsedLine::Instr(Instr::Len),
+            "concat" => {
+                let count = Self::parse_number(operands.next()?)?;
+                if count < 0 {
+                    return Err("negative string count".to_owned());
                 }
-                &Instr::Add => {
-                    let (arg1, arg2) = (self.stack.pop_number(), self.stack.pop_number());
-                    self.stack.push(StackVal::Number(arg1 + arg2));
-                    self.pc += 1;
+                ParsedLine::Instr(Instr::Concat(count as usize))
+            }
+            "typeof" => ParsedLine::Instr(Instr::TypeOf),
+            "print" => ParsedLine::Instr(Instr::Print),
+            "tee" => ParsedLine::Instr(Instr::Tee),
+            "printbase" => ParsedLine::Instr(Instr::PrintBase),
+            "peekn" => {
+                let depth = Self::parse_number(operands.next()?)?;
+                if depth < 0 {
+                    return Err("negative stack depth".to_owned());
                 }
-                &Instr::Dup => {
-                    let val = self.stack.pop();
-                    self.stack.push(val.clone());
-                    self.stack.push(val);
-                    self.pc += 1;
+                ParsedLine::Instr(Instr::PeekN(depth as usize))
+            }
+            "printall" => ParsedLine::Instr(Instr::PrintAll),
+            "hash" => ParsedLine::Instr(Instr::Hash),
+            "shuffle" => ParsedLine::Instr(Instr::Shuffle),
+            "checkpoint" => ParsedLine::Instr(Instr::Checkpoint),
+            "rollback" => ParsedLine::Instr(Instr::Rollback),
+            "lt" => ParsedLine::Instr(Instr::Compare(CmpKind::Lt)),
+            "le" => ParsedLine::Instr(Instr::Compare(CmpKind::Le)),
+            "gt" => ParsedLine::Instr(Instr::Compare(CmpKind::Gt)),
+            "ge" => ParsedLine::Instr(Instr::Compare(CmpKind::Ge)),
+            "eq" => ParsedLine::Instr(Instr::Compare(CmpKind::Eq)),
+            "ne" => ParsedLine::Instr(Instr::Compare(CmpKind::Ne)),
+            "land" => ParsedLine::Instr(Instr::LogicalAnd),
+            "lor" => ParsedLine::Instr(Instr::LogicalOr),
+            "pop" => ParsedLine::Instr(Instr::Pop),
+            "nop" => ParsedLine::Instr(Instr::Nop),
+            "sleep" => {
+                let ms = Self::parse_number(operands.next()?)?;
+                if ms < 0 {
+                    return Err("negative sleep time".to_owned());
                 }
-                &Instr::Sub => {
-                    let (arg1, arg2) = (self.stack.pop_number(), self.stack.pop_number());
-                    self.stack.push(StackVal::Number(arg2 - arg1));
-                    self.pc += 1;
+                ParsedLine::Instr(Instr::Sleep(ms as u64))
+            }
+            "usestack" => ParsedLine::Instr(Instr::UseStack(String::from(operands.next()?))),
+            "movetop" => {
+                let src = operands.next()?;
+                let dst = operands.next()?;
+                ParsedLine::Instr(Instr::MoveTop(String::from(src), String::from(dst)))
+            }
+            "memload" => ParsedLine::Instr(Instr::MemLoad),
+            "memstore" => ParsedLine::Instr(Instr::MemStore),
+            "memclear" => ParsedLine::Instr(Instr::MemClear),
+            "memdump" => ParsedLine::Instr(Instr::MemDump),
+            "store" => ParsedLine::Instr(Instr::Store(String::from(operands.next()?))),
+            "load" => ParsedLine::Instr(Instr::Load(String::from(operands.next()?))),
+            "incvar" => ParsedLine::Instr(Instr::IncVar(String::from(operands.next()?))),
+            "decvar" => ParsedLine::Instr(Instr::DecVar(String::from(operands.next()?))),
+            "watch" => ParsedLine::Instr(Instr::Watch(String::from(operands.next()?))),
+            "dup" => ParsedLine::Instr(Instr::Dup),
+            "rollup" => ParsedLine::Instr(Instr::RollUp),
+            "rolldown" => ParsedLine::Instr(Instr::RollDown),
+            "2swap" => ParsedLine::Instr(Instr::Swap2),
+            "remove" => {
+                let depth = Self::parse_number(operands.next()?)?;
+                if depth < 0 {
+                    return Err("negative stack depth".to_owned());
                 }
-                &Instr::Print => {
-                    let arg = self.stack.pop_number();
-                    println!("{}", arg);
-                    self.pc += 1;
+                ParsedLine::Instr(Instr::Remove(depth as usize))
+            }
+            "dupnz" => ParsedLine::Instr(Instr::DupIfNonZero),
+            "rotn" => {
+                let n = Self::parse_number(operands.next()?)?;
+                if n < 0 {
+                    return Err("negative element count".to_owned());
                 }
-                &Instr::Pop => {
-                    let _ = self.stack.pop();
-                    self.pc += 1;
+                ParsedLine::Instr(Instr::RotN(n as usize))
+            }
+            "if" => ParsedLine::If,
+            "else" => ParsedLine::Else,
+            "endif" => ParsedLine::EndIf,
+            "while" => ParsedLine::While,
+            "endwhile" => ParsedLine::EndWhile,
+            "repeat" => {
+                let var = operands.next()?;
+                let count = Self::parse_number(operands.next()?)?;
+                if count < 0 {
+                    return Err("negative repeat count".to_owned());
                 }
-                // XXX generalise binary operations to reduce duplication
-                &Instr::JumpNotEqual(ref cmp_val, ref label) => {
-                    let val = self.stack.pop_number();
-                    if val != *cmp_val {
-                        if let Some(addr) = self.labels.get(label) {
-                            self.pc = *addr;
-                        } else {
-                            fatal("undefined label");
-                        }
-                    } else {
-                        self.pc += 1;
-                    }
+                ParsedLine::Repeat(var.to_owned(), count)
+            }
+            "endrepeat" => ParsedLine::EndRepeat,
+            "jmp" => ParsedLine::Instr(Instr::Jump(Self::user_target(labels, operands.next()?)?)),
+            "jrel" => {
+                let offset = Self::parse_number(operands.next()?)?;
+                let (min, max) = (i32::MIN as RawNumber, i32::MAX as RawNumber);
+                if offset < min || offset > max {
+                    return Err("jump offset out of range".to_owned());
                 }
-                &Instr::JumpEqual(ref cmp_val, ref label) => {
-                    let val = self.stack.pop_number();
-                    if val == *cmp_val {
-                        if let Some(addr) = self.labels.get(label) {
-                            self.pc = *addr;
-                        } else {
-                            fatal("undefined label");
-                        }
-                    } else {
-                        self.pc += 1;
+                ParsedLine::Instr(Instr::JumpRel(offset as i32))
+            }
+            "call" => ParsedLine::Instr(Instr::Call(Self::user_target(labels, operands.next()?)?)),
+            "ret" => ParsedLine::Instr(Instr::Ret),
+            "try" => ParsedLine::Instr(Instr::Try(Self::user_target(labels, operands.next()?)?)),
+            "endtry" => ParsedLine::Instr(Instr::EndTry),
+            "calln" => {
+                let target = Self::user_target(labels, operands.next()?)?;
+                let argc = Self::parse_number(operands.next()?)?;
+                if argc < 0 {
+                    return Err("negative argument count".to_owned());
+                }
+                ParsedLine::Instr(Instr::CallN(target, argc as usize))
+            }
+            "retn" => {
+                let retc = Self::parse_number(operands.next()?)?;
+                if retc < 0 {
+                    return Err("negative return count".to_owned());
+                }
+                ParsedLine::Instr(Instr::RetN(retc as usize))
+            }
+            "pushpc" => ParsedLine::Instr(Instr::PushPc),
+            "je" => {
+                let cmp_val = Self::parse_number(operands.next()?)?;
+                let target = Self::user_target(labels, operands.next()?)?;
+                ParsedLine::Instr(Instr::JumpEqual(cmp_val, target))
+            }
+            "jne" => {
+                let cmp_val = Self::parse_number(operands.next()?)?;
+                let target = Self::user_target(labels, operands.next()?)?;
+                ParsedLine::Instr(Instr::JumpNotEqual(cmp_val, target))
+            }
+            "exit" => {
+                let code = Self::parse_number(operands.next()?)?;
+                // Process exit codes are a single byte, so anything else would be truncated.
+                if code < 0 || code > 255 {
+                    return Err("exit code must be between 0 and 255".to_owned());
+                }
+                ParsedLine::Instr(Instr::Exit(code as i32))
+            }
+            "jneg" => {
+                let target = Self::user_target(labels, operands.next()?)?;
+                ParsedLine::Instr(Instr::JumpNegative(target))
+            }
+            "jzero" => {
+                let target = Self::user_target(labels, operands.next()?)?;
+                ParsedLine::Instr(Instr::JumpZero(target))
+            }
+            "jpos" => {
+                let target = Self::user_target(labels, operands.next()?)?;
+                ParsedLine::Instr(Instr::JumpPositive(target))
+            }
+            "jifempty" => {
+                let target = Self::user_target(labels, operands.next()?)?;
+                ParsedLine::Instr(Instr::JumpIfEmpty(target))
+            }
+            "jifdepth" => {
+                let depth = Self::parse_number(operands.next()?)?;
+                if depth < 0 {
+                    return Err("negative stack depth".to_owned());
+                }
+                let target = Self::user_target(labels, operands.next()?)?;
+                ParsedLine::Instr(Instr::JumpIfShallow(depth as usize, target))
+            }
+            "switch" => {
+                // `value:label` cases followed by the default label, e.g. `switch 1:a, 2:b, c`.
+                let mut cases = Vec::new();
+                let mut default = operands.next()?;
+                while let Some(tok) = operands.next_optional() {
+                    let (val, label) = match default.find(':') {
+                        Some(i) => (&default[..i], &default[i + 1..]),
+                        None => return Err(format!("expected value:label, found '{}'", default)),
+                    };
+                    cases.push((Self::parse_number(val)?, Self::user_target(labels, label)?));
+                    default = tok;
+                }
+                ParsedLine::Instr(Instr::Switch(cases, Self::user_target(labels, default)?))
+            }
+            "readall" => ParsedLine::Instr(Instr::ReadAll),
+            "readline" => ParsedLine::Instr(Instr::ReadLine),
+            "read" => {
+                // An optional radix for the number read, e.g. `read 16` for hexadecimal.
+                let radix = match operands.next_optional() {
+                    Some(s) => Self::parse_number(s)?,
+                    None => 10,
+                };
+                if radix < 2 || radix > 36 {
+                    return Err("radix must be between 2 and 36".to_owned());
+                }
+                ParsedLine::Instr(Instr::Read(radix as u32))
+            }
+            "push" => {
+                let tok = operands.next()?;
+                let val = if tok.starts_with('"') {
+                    StackVal::Str(Self::parse_string(tok)?)
+                } else if tok.contains('.') {
+                    StackVal::Float(Self::parse_float(tok)?)
+                } else {
+                    StackVal::Number(Self::parse_number(tok)?)
+                };
+                ParsedLine::Instr(Instr::Push(val))
+            }
+            "expectstack" => {
+                let mut vals = Vec::new();
+                while let Some(tok) = operands.next_optional() {
+                    vals.push(Self::parse_number(tok)?);
+                }
+                ParsedLine::Instr(Instr::ExpectStack(vals))
+            }
+            "range" => ParsedLine::Instr(Instr::Range(Self::parse_number(operands.next()?)?)),
+            "addall" => ParsedLine::Instr(Instr::AddAll(Self::parse_number(operands.next()?)?)),
+            ".data" => {
+                // `.data NAME "path"` loads the numbers in a file, for `pushdata NAME`. A
+                // relative path is relative to the current directory, not the program's.
+                let name = operands.next()?;
+                let path = operands.next()?;
+                if !path.starts_with('"') {
+                    return Err("expected a quoted file name".to_owned());
+                }
+                ParsedLine::Data(name.to_owned(), Self::parse_string(path)?)
+            }
+            "pushdata" => ParsedLine::PushData(operands.next()?.to_owned()),
+            _ => {
+                if opcode.ends_with(":") {
+                    if opcode.starts_with(INTERNAL_LABEL_PREFIX) {
+                        return Err("labels starting with '$' are reserved".to_owned());
                     }
+                    // XXX in a real interpreter you would resolve the labels to addresses
+                    // ahead of time so that: a) a bad label is compile-time detected, and b)
+                    // you don't have to repeatedly look them up.
+                    ParsedLine::Label(opcode[..opcode.len() - 1].to_owned())
+                } else {
+                    return Err(format!("unknown opcode '{}'", opcode));
                 }
             }
-        }
+        };
+        Ok((rv, cols))
     }
-}
-
-#[derive(Clone)]
-enum Instr {
-    Push(StackVal),
-    Pop,
-    Add,
-    Dup,
-    Sub,
-    JumpEqual(RawNumber, LabelName), // jump to .1 if top of stack == .0
-    JumpNotEqual(RawNumber, LabelName), // jump to .1 if top of stack != .0
-    Print,
-}
 
-#[derive(Clone)]
-enum ParsedLine {
-    Label(LabelName),
-    Instr(Instr),
-}
+    // Statically check the program, returning a description of each problem found.
+    pub fn verify(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (addr, &(ref instr, _)) in self.program.iter().enumerate() {
+            for target in instr.jump_targets() {
+                if self.labels.address(target.label).is_none() {
+                    problems.push(format!(
+                        "instruction {} ({}): undefined label '{}'",
+                        addr,
+                        instr.opcode(),
+                        self.labels.name(target.label)
+                    ));
+                }
+            }
+        }
+        problems
+    }
 
-#[derive(Clone)]
-enum StackVal {
-    Number(RawNumber),
-}
+    // Resolve the target of every jump to an address up front, so that taking a jump doesn't
+    // need to look up its label. Fails if a jump refers to an undefined label.
+    pub fn link(&mut self) -> Result<(), InterpError> {
+        for &mut (ref mut instr, _) in self.program.iter_mut() {
+            for target in instr.jump_targets_mut() {
+                match self.labels.address(target.label) {
+                    Some(addr) => target.addr = Some(addr),
+                    None => {
+                        let name = self.labels.name(target.label).to_owned();
+                        return Err(InterpError::UndefinedLabel(name));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-struct Stack {
-    stack: Vec<StackVal>,
-}
+    // Rewrite the program to do the same work in fewer instructions: `nop`s are removed, `push X`
+    // `pop` pairs are removed, and additions and subtractions of pushed numbers are folded into a
+    // single push (unless they would overflow, which is left to fail at run-time). Instructions
+    // are never combined across a label (or the target of a `jrel`), and labels and jumps are
+    // moved to match. This changes the addresses of instructions, so it should be done before
+    // the program runs. A program which uses `pushpc` can see those addresses, so it is left
+    // unchanged.
+    pub fn optimize(&mut self) {
+        self.rewrite(true);
+    }
 
-impl Stack {
-    fn new() -> Self {
-        Stack { stack: vec![] }
+    // Fold constant arithmetic as `optimize` does, but leave `nop`s and `push X` `pop` pairs
+    // alone. Parsing doesn't fold, so this must be asked for. Like `optimize`, it moves
+    // instructions, so addresses reported afterwards (e.g. by `verify`, `to_json` or the trace)
+    // are those of the folded program.
+    pub fn fold_constants(&mut self) {
+        self.rewrite(false);
     }
 
-    fn push(&mut self, val: StackVal) {
-        self.stack.push(val);
+    // The code behind `optimize`, which only folds constants unless `all` is true.
+    fn rewrite(&mut self, all: bool) {
+        let uses_pc = self.program.iter().any(|&(ref instr, _)| match instr {
+            &Instr::PushPc => true,
+            _ => false,
+        });
+        if uses_pc {
+            return;
+        }
+        let len = self.program.len();
+        let mut labelled = vec![false; len];
+        for &addr in self.labels.addrs.iter().flatten() {
+            if addr < len {
+                labelled[addr] = true;
+            }
+        }
+        // The targets of relative jumps count as labelled too.
+        for (addr, &(ref instr, _)) in self.program.iter().enumerate() {
+            match instr.rel_target(addr, len) {
+                Some(target) if target < len => labelled[target] = true,
+                _ => (),
+            }
+        }
+        let has_spans = self.spans.len() == len;
+        let old_spans = mem::replace(&mut self.spans, Vec::new());
+        // For each instruction kept, the address of the first original instruction it replaces.
+        let mut starts: Vec<usize> = Vec::new();
+        let mut out: Program = Vec::new();
+        let program = mem::replace(&mut self.program, Vec::new());
+        for (addr, (instr, loc)) in program.into_iter().enumerate() {
+            // Whether `instr` can be combined with the instruction `depth` places from the end.
+            // Neither it, nor anything after that instruction, may be the target of a jump.
+            let combinable = |depth: usize, starts: &[usize]| {
+                starts.len() >= depth
+                    && !labelled[addr]
+                    && starts[starts.len() - depth + 1..].iter().all(|&start| !labelled[start])
+            };
+            let folded = match (&instr, out.len()) {
+                (&Instr::Nop, _) if all => continue,
+                (&Instr::Pop, n) if all && combinable(1, &starts) => match out[n - 1].0 {
+                    Instr::Push(_) => {
+                        out.pop();
+                        starts.pop();
+                        continue;
+                    }
+                    _ => None,
+                },
+                (&Instr::Add, n) | (&Instr::Sub, n) | (&Instr::RSub, n)
+                    if combinable(2, &starts) =>
+                {
+                    match (&out[n - 2].0, &out[n - 1].0) {
+                        (&Instr::Push(StackVal::Number(a)), &Instr::Push(StackVal::Number(b))) => {
+                            match &instr {
+                                &Instr::Add => a.checked_add(b),
+                                &Instr::Sub => a.checked_sub(b),
+                                _ => b.checked_sub(a),
+                            }
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            match folded {
+                Some(val) => {
+                    out.pop();
+                    starts.pop();
+                    let n = out.len();
+                    out[n - 1].0 = Instr::Push(StackVal::Number(val));
+                }
+                None => {
+                    out.push((instr, loc));
+                    starts.push(addr);
+                }
+            }
+        }
+
+        // A label moves to the first kept instruction at or after its old address.
+        let new_addr = |addr: usize| starts.iter().take_while(|&&start| start < addr).count();
+        for label_addr in self.labels.addrs.iter_mut() {
+            if let Some(addr) = *label_addr {
+                *label_addr = Some(new_addr(addr));
+            }
+        }
+        for (new, &mut (ref mut instr, _)) in out.iter_mut().enumerate() {
+            for target in instr.jump_targets_mut() {
+                if target.addr.is_some() {
+                    target.addr = self.labels.address(target.label);
+                }
+            }
+            if let Some(target) = instr.rel_target(starts[new], len) {
+                *instr = Instr::JumpRel((new_addr(target) as i64 - new as i64) as i32);
+            }
+        }
+        if has_spans {
+            self.spans = starts.iter().map(|&start| old_spans[start].clone()).collect();
+        }
+        self.program = out;
     }
 
-    fn pop(&mut self) -> StackVal {
-        let val = self.stack.pop();
-        if val.is_none() {
-            fatal("stack underflow");
+    // Returns the addresses of instructions that can never be executed, in ascending order.
+    pub fn find_unreachable(&self) -> Vec<usize> {
+        let mut reached = vec![false; self.program.len()];
+        let mut todo = vec![0];
+        while let Some(addr) = todo.pop() {
+            if addr >= self.program.len() || reached[addr] {
+                continue;
+            }
+            reached[addr] = true;
+            let instr = &self.program[addr].0;
+            for target in instr.jump_targets() {
+                if let Some(target) = target.resolve(&self.labels) {
+                    todo.push(target);
+                }
+            }
+            if let Some(target) = instr.rel_target(addr, self.program.len()) {
+                todo.push(target);
+            }
+            if instr.falls_through() {
+                todo.push(addr + 1);
+            }
         }
-        val.unwrap()
+        reached.iter().enumerate().filter(|&(_, r)| !r).map(|(addr, _)| addr).collect()
     }
 
-    fn pop_number(&mut self) -> RawNumber {
-        let item = self.pop();
-        let rv = match item {
-            StackVal::Number(val) => val,
-        };
+    // Returns the address and name of each label which the preceding instruction can fall
+    // through into, i.e. which can be reached other than by a jump. Labels generated for
+    // structured control flow are ignored.
+    pub fn find_fall_through_labels(&self) -> Vec<(usize, &str)> {
+        let mut rv = Vec::new();
+        for (id, name) in self.labels.names.iter().enumerate() {
+            if name.starts_with(INTERNAL_LABEL_PREFIX) {
+                continue;
+            }
+            if let Some(addr) = self.labels.address(id) {
+                if addr > 0 && self.program[addr - 1].0.falls_through() {
+                    rv.push((addr, name.as_str()));
+                }
+            }
+        }
+        rv.sort();
         rv
     }
-}
-
-pub fn fatal(msg: &str) -> ! {
-    println!("FATAL: {}", msg);
-    exit(1);
-}
 
+    // The program's control flow graph in Graphviz DOT format. Nodes are basic blocks, which
+    // start at the program's entry, at labels and after instructions that may jump. Solid edges
+    // are jumps (and calls), dashed edges are fall-through. `ret` has no edges, since where it
+    // goes depends on the call.
+    pub fn to_dot(&self) -> String {
+        let len = self.program.len();
+        let mut starts = vec![false; len];
+        let mut names = vec![Vec::new(); len]; // the labels at each address
+        for (id, name) in self.labels.names.iter().enumerate() {
+            if let Some(addr) = self.labels.address(id) {
+                if addr < len {
+                    starts[addr] = true;
+                    names[addr].push(name.as_str());
+                }
+            }
+        }
+        if len > 0 {
+            starts[0] = true;
+        }
+        for (addr, &(ref instr, _)) in self.program.iter().enumerate() {
+            if addr + 1 < len && (instr.may_jump() || !instr.falls_through()) {
+                starts[addr + 1] = true;
+            }
+            match instr.rel_target(addr, len) {
+                Some(target) if target < len => starts[target] = true,
+                _ => (),
+            }
+        }
+
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut rv = String::from("digraph program {\n");
+        rv.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        let mut edges = Vec::new();
+        let mut addr = 0;
+        while addr < len {
+            let start = addr;
+            let mut text = String::new();
+            loop {
+                for name in &names[addr] {
+                    text.push_str(&format!("{}:\\l", escape(name)));
+                }
+                text.push_str(&format!("{}: {}\\l", addr, self.program[addr].0.opcode()));
+                addr += 1;
+                if addr == len || starts[addr] {
+                    break;
+                }
+            }
+            rv.push_str(&format!("    b{} [label=\"{}\"];\n", start, text));
+            let last = &self.program[addr - 1].0;
+            let dsts = last.jump_targets().into_iter().filter_map(|t| t.resolve(&self.labels));
+            for dst in dsts.chain(last.rel_target(addr - 1, len)) {
+                if dst < len {
+                    edges.push(format!("    b{} -> b{};\n", start, dst));
+                }
+            }
+            if last.falls_through() && addr < len {
+                edges.push(format!("    b{} -> b{} [style=dashed];\n", start, addr));
+            }
+        }
+        for edge in edges {
+            rv.push_str(&edge);
+        }
+        rv.push_str("}\n");
+        rv
+    }
+
+    // Each instruction's address, opcode and stack effect (see `Instr::stack_effect`), in order.
+    pub fn stack_effects(&self) -> Vec<(usize, &'static str, Option<(usize, usize)>)> {
+        self.program
+            .iter()
+            .enumerate()
+            .map(|(addr, &(ref instr, _))| (addr, instr.opcode(), instr.stack_effect()))
+            .collect()
+    }
+
+    // How many times each opcode appears in the program, most frequent first. Ties are in opcode
+    // order. This counts the program text, not how often each instruction runs.
+    pub fn opcode_counts(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: HashMap<&'static str, usize> = HashMap::new();
+        for &(ref instr, _) in &self.program {
+            *counts.entry(instr.opcode()).or_insert(0) += 1;
+        }
+        let mut counts: Vec<(&'static str, usize)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    // The meta-tracer location attached to each instruction, by instruction index. Inspecting
+    // these after a run shows which control points the meta-tracer has acted upon.
+    pub fn location_report(&self) -> Vec<(usize, &Location)> {
+        self.program.iter().enumerate().map(|(addr, &(_, ref loc))| (addr, loc)).collect()
+    }
+
+    // The parsed program as JSON, for external tools. The format is:
+    //
+    //   {"program": [{"addr": 0, "opcode": "je", "operands": [{"number": 0}, {"label": "l"}],
+    //                 "span": {"line": 1, "start": 2, "end": 4}},
+    //                ...],
+    //    "labels": {"l": 3, ...}}
+    //
+    // "span" is the instruction's `Span`, and is omitted if it isn't known.
+    // Each operand is an object with one key giving its kind: "number", "float" (null if not
+    // finite), "bool" or "string" for values; "label" for jump targets; "name" for variable and
+    // stack names. A `switch` case is {"case": <number>, "label": <name>}. "labels" maps each
+    // defined label, including those generated for structured control flow, to its address.
+    pub fn to_json(&self) -> String {
+        let mut instrs = Vec::new();
+        for (addr, &(ref instr, _)) in self.program.iter().enumerate() {
+            let span = match self.span(addr) {
+                Some(span) => format!(
+                    ", \"span\": {{\"line\": {}, \"start\": {}, \"end\": {}}}",
+                    span.line, span.start, span.end
+                ),
+                None => String::new(),
+            };
+            instrs.push(format!(
+                "{{\"addr\": {}, \"opcode\": {}, \"operands\": [{}]{}}}",
+                addr,
+                json_string(instr.opcode()),
+                instr.json_operands(&self.labels).join(", "),
+                span
+            ));
+        }
+        let mut labels = Vec::new();
+        for (id, name) in self.labels.names.iter().enumerate() {
+            if let Some(addr) = self.labels.address(id) {
+                labels.push(format!("{}: {}", json_string(name), addr));
+            }
+        }
+        format!(
+            "{{\"program\": [\n  {}\n], \"labels\": {{{}}}}}",
+            instrs.join(",\n  "),
+            labels.join(", ")
+        )
+    }
+
+    // main interpreter loop. A runtime error is fatal; use `run_to_result` to handle it instead.
+    pub fn run(&mut self) -> Outcome {
+        match self.run_to_result() {
+            Ok(outcome) => outcome,
+            Err(e) => fatal(&e.to_string()),
+        }
+    }
+
+    // Like `run`, but a runtime error is returned rather than ending the process. The
+    // interpreter is left as it was when the error occurred.
+    pub fn run_to_result(&mut self) -> Result<Outcome, RuntimeError> {
+        let tracer = TracerBuilder::new().build().unwrap();
+        let mt = MetaTracer::new(tracer);
+        loop {
+            if let Some(&(_, ref loc)) = self.program.get(self.pc) {
+                mt.control_point(loc);
+            }
+            if let Some(outcome) = self.step()? {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    // Parse and run `line`, which holds any number of instructions, e.g. `push 2 push 3 add`,
+    // and return the resulting top of stack, which must be a number. The stacks and variables
+    // are those of the interpreter, so a calculator can build on the results of earlier lines.
+    // The interpreter's own program and pc are left untouched. Labels, blocks and instructions
+    // which jump aren't allowed. An instruction with optional operands (e.g. `read`) takes as
+    // many of the following tokens as it can.
+    pub fn eval_expr(&mut self, line: &str) -> Result<RawNumber, InterpError> {
+        let parse_err = |msg: String| InterpError::Parse { line: 1, msg: msg };
+        let mut operands = Operands::new(line).map_err(parse_err)?;
+        let mut labels = LabelInterner::new();
+        let mut program: Program = Vec::new();
+        while !operands.at_end() {
+            match Self::parse_operands(line, &mut operands, &mut labels).map_err(parse_err)? {
+                (ParsedLine::Instr(ref instr), _) if instr.may_jump() => {
+                    let msg = format!("'{}' can't be used in an expression", instr.opcode());
+                    return Err(parse_err(msg));
+                }
+                (ParsedLine::Instr(instr), _) => program.push((instr, Location::new())),
+                _ => {
+                    let msg = "only instructions can be used in an expression".to_owned();
+                    return Err(parse_err(msg));
+                }
+            }
+        }
+
+        let saved_program = mem::replace(&mut self.program, program);
+        let saved_pc = mem::replace(&mut self.pc, 0);
+        let mut rv = Ok(());
+        while self.pc < self.program.len() {
+            match self.step() {
+                Ok(None) => (),
+                Ok(Some(_)) => break, // `exit`
+                Err(e) => {
+                    rv = Err(e);
+                    break;
+                }
+            }
+        }
+        self.program = saved_program;
+        self.pc = saved_pc;
+        rv.and_then(|_| match self.stack.top()? {
+            &StackVal::Number(n) => Ok(n),
+            val => Err(type_mismatch("number", val)),
+        })
+        .map_err(InterpError::Runtime)
+    }
+
+    // Execute instructions until the pc reaches the address of label `name`, or the program
+    // ends. At least one instruction is executed, so that repeated calls stop at each visit to
+    // the label in turn. Fails if the label isn't defined.
+    pub fn run_until_label(&mut self, name: &str) -> Result<(), RuntimeError> {
+        let addr = match self.labels.lookup(name).and_then(|id| self.labels.address(id)) {
+            Some(addr) => addr,
+            None => return Err(RuntimeError::UndefinedLabel(name.to_owned())),
+        };
+        loop {
+            if self.step()?.is_some() || self.pc == addr {
+                return Ok(());
+            }
+        }
+    }
+
+    // Execute a single instruction. Returns the outcome if the program has ended, or None if
+    // there is more to run. Unlike `run`, this doesn't pass through the meta-tracer.
+    //
+    // If the instruction fails while a `try` is active, the error is caught: the innermost `try`
+    // ends, any `call`s made since it are abandoned, the error's code (see `RuntimeError::code`)
+    // is pushed on to the active stack and execution continues at the `try`'s label. The rest of
+    // the stack is left as the failed instruction left it. Limit errors (see
+    // `RuntimeError::is_limit`) are never caught.
+    pub fn step(&mut self) -> Result<Option<Outcome>, RuntimeError> {
+        let rv = self.exec().or_else(|e| {
+            if !e.is_limit() {
+                if let Some(handler) = self.handlers.pop() {
+                    self.calls.truncate(handler.calls);
+                    self.stack.push(StackVal::Number(e.code()));
+                    self.pc = handler.addr;
+                    return Ok(None);
+                }
+            }
+            Err(e)
+        });
+        // `exec` only sees the depth before each instruction, which misses the last one's result.
+        self.max_depth = self.max_depth.max(self.stack.depth());
+        rv
+    }
+
+    // Execute a single instruction, as `step` does, but without catching errors.
+    fn exec(&mut self) -> Result<Option<Outcome>, RuntimeError> {
+        let instr = match self.program.get(self.pc) {
+            None => return Ok(Some(Outcome::Finished)), // end of program.
+            Some(&(ref instr, _)) => instr,
+        };
+        if let Some(max) = self.max_steps {
+            if self.steps >= max {
+                return Err(RuntimeError::StepLimitExceeded);
+            }
+        }
+        self.steps += 1;
+        let depth = self.stack.depth();
+        if depth >= self.depths.len() {
+            self.depths.resize(depth + 1, 0);
+        }
+        self.depths[depth] += 1;
+        self.max_depth = self.max_depth.max(depth);
+
+        let from = self.pc;
+        let trace_jump = self.trace.is_some() && self.trace_jumps_only && instr.may_jump();
+        if let Some(ref mut out) = self.trace {
+            if !self.trace_jumps_only
+                && writeln!(out, "{} {} {}", self.pc, instr.opcode(), self.stack.depth()).is_err()
+            {
+                return Err(RuntimeError::TraceWrite);
+            }
+        }
+
+        match instr {
+            &Instr::Push(ref val) => {
+                self.stack.push(val.clone());
+                self.pc += 1;
+            }
+            &Instr::AddAll(n) => {
+                self.stack.map_numbers(|val| val.checked_add(n))?;
+                self.pc += 1;
+            }
+            &Instr::PushData(_, ref vals) => {
+                for &val in vals {
+                    self.stack.push(StackVal::Number(val));
+                }
+                self.pc += 1;
+            }
+            &Instr::Range(n) => {
+                if n < 0 {
+                    return Err(RuntimeError::InvalidArgument("range of negative length"));
+                }
+                for i in 0..n {
+                    self.stack.push(StackVal::Number(i));
+                }
+                self.pc += 1;
+            }
+            &Instr::Add => {
+                // Numbers are added, strings are concatenated.
+                let (arg1, arg2) = (self.stack.pop()?, self.stack.pop()?);
+                let val = match (arg2, arg1) {
+                    (StackVal::Str(a), StackVal::Str(b)) => StackVal::Str(a + &b),
+                    (StackVal::Str(_), b) => return Err(type_mismatch("string", &b)),
+                    (a @ StackVal::Bool(_), _) => {
+                        return Err(type_mismatch("number or string", &a))
+                    }
+                    (a, b) => arith(a, b, RawNumber::checked_add, |a, b| a + b)?,
+                };
+                self.stack.push(val);
+                self.pc += 1;
+            }
+            &Instr::Dup => {
+                let val = self.stack.pop()?;
+                self.stack.push(val.clone());
+                self.stack.push(val);
+                self.pc += 1;
+            }
+            &Instr::RollUp => {
+                self.stack.roll_up();
+                self.pc += 1;
+            }
+            &Instr::RollDown => {
+                self.stack.roll_down();
+                self.pc += 1;
+            }
+            &Instr::Swap2 => {
+                // Check up front, so that an underflow leaves the stack intact.
+                if self.stack.depth() < 4 {
+                    return Err(RuntimeError::StackUnderflow);
+                }
+                let (d, c, b, a) = (self.stack.pop()?, self.stack.pop()?, self.stack.pop()?,
+                                    self.stack.pop()?);
+                self.stack.push(c);
+                self.stack.push(d);
+                self.stack.push(a);
+                self.stack.push(b);
+                self.pc += 1;
+            }
+            &Instr::Remove(depth) => {
+                self.stack.remove(depth)?;
+                self.pc += 1;
+            }
+            &Instr::RotN(n) => {
+                if n > 0 {
+                    let deepest = self.stack.remove(n - 1)?;
+                    self.stack.push(deepest);
+                }
+                self.pc += 1;
+            }
+            &Instr::DupIfNonZero => {
+                match *self.stack.top()? {
+                    StackVal::Number(0) => (),
+                    StackVal::Number(val) => self.stack.push(StackVal::Number(val)),
+                    ref val => return Err(type_mismatch("number", val)),
+                }
+                self.pc += 1;
+            }
+            &Instr::Sub => {
+                let (arg1, arg2) = (self.stack.pop()?, self.stack.pop()?);
+                self.stack.push(arith(arg2, arg1, RawNumber::checked_sub, |a, b| a - b)?);
+                self.pc += 1;
+            }
+            &Instr::RSub => {
+                let (arg1, arg2) = (self.stack.pop()?, self.stack.pop()?);
+                self.stack.push(arith(arg1, arg2, RawNumber::checked_sub, |a, b| a - b)?);
+                self.pc += 1;
+            }
+            &Instr::ToInt => {
+                let val = match self.stack.pop()? {
+                    StackVal::Float(x) => {
+                        // `as` would saturate, and turn NaN into 0, so check the range first.
+                        // As a float, the maximum may round up to the next power of two, which
+                        // is out of range, hence the exclusive upper bound.
+                        let x = x.trunc();
+                        let (min, max) = (RawNumber::MIN as f64, RawNumber::MAX);
+                        if !(x >= min && x < max as f64) {
+                            return Err(RuntimeError::ArithmeticOverflow);
+                        }
+                        x as RawNumber
+                    }
+                    StackVal::Number(n) => n,
+                    ref val => return Err(type_mismatch("number or float", val)),
+                };
+                self.stack.push(StackVal::Number(val));
+                self.pc += 1;
+            }
+            &Instr::ToFloat => {
+                let val = match self.stack.pop()? {
+                    StackVal::Number(n) => n as f64,
+                    StackVal::Float(x) => x,
+                    ref val => return Err(type_mismatch("number or float", val)),
+                };
+                self.stack.push(StackVal::Float(val));
+                self.pc += 1;
+            }
+            &Instr::Log2 => {
+                let arg = self.stack.pop_number()?;
+                if arg <= 0 {
+                    return Err(RuntimeError::InvalidArgument("log2 of non-positive"));
+                }
+                // The index of the highest set bit.
+                let log = RawNumber::BITS - 1 - arg.leading_zeros();
+                self.stack.push(StackVal::Number(log as RawNumber));
+                self.pc += 1;
+            }
+            &Instr::DivMod => {
+                let (divisor, dividend) = (self.stack.pop_number()?, self.stack.pop_number()?);
+                if divisor == 0 {
+                    return Err(RuntimeError::DivideByZero);
+                }
+                match (dividend.checked_div(divisor), dividend.checked_rem(divisor)) {
+                    (Some(quot), Some(rem)) => {
+                        self.stack.push(StackVal::Number(quot));
+                        self.stack.push(StackVal::Number(rem));
+                    }
+                    _ => return Err(RuntimeError::ArithmeticOverflow),
+                }
+                self.pc += 1;
+            }
+            &Instr::Digits => {
+                let arg = self.stack.pop_number()?;
+                if arg < 0 {
+                    return Err(RuntimeError::InvalidArgument("digits of negative"));
+                }
+                let digits: Vec<RawNumber> =
+                    arg.to_string().bytes().map(|b| (b - b'0') as RawNumber).collect();
+                let count = digits.len() as RawNumber;
+                for digit in digits {
+                    self.stack.push(StackVal::Number(digit));
+                }
+                self.stack.push(StackVal::Number(count));
+                self.pc += 1;
+            }
+            &Instr::Sqrt => {
+                let arg = self.stack.pop_number()?;
+                if arg < 0 {
+                    return Err(RuntimeError::InvalidArgument("sqrt of negative"));
+                }
+                self.stack.push(StackVal::Number(isqrt(arg)));
+                self.pc += 1;
+            }
+            &Instr::Half => {
+                let arg = self.stack.pop_number()?;
+                self.stack.push(StackVal::Number(arg / 2));
+                self.pc += 1;
+            }
+            &Instr::IsEven => {
+                let even = match self.stack.top()? {
+                    &StackVal::Number(n) => n % 2 == 0,
+                    val => return Err(type_mismatch("number", val)),
+                };
+                self.stack.push(StackVal::Bool(even));
+                self.pc += 1;
+            }
+            &Instr::Read(radix) => {
+                let mut line = String::new();
+                match self.input.read_line(&mut line) {
+                    Ok(0) => return Err(RuntimeError::EndOfInput),
+                    Ok(_) => (),
+                    Err(_) => return Err(RuntimeError::ReadFailed),
+                }
+                let line = line.trim();
+                match RawNumber::from_str_radix(line, radix) {
+                    Ok(val) => self.stack.push(StackVal::Number(val)),
+                    Err(_) => {
+                        let text = line.to_owned();
+                        return Err(RuntimeError::InvalidNumber { radix: radix, text: text });
+                    }
+                }
+                self.pc += 1;
+            }
+            &Instr::ReadLine => {
+                let mut line = String::new();
+                match self.input.read_line(&mut line) {
+                    Ok(0) => self.stack.push(StackVal::Bool(false)),
+                    Ok(_) => {
+                        let line = line.trim();
+                        match line.parse::<RawNumber>() {
+                            Ok(val) => self.stack.push(StackVal::Number(val)),
+                            Err(_) => {
+                                let text = line.to_owned();
+                                return Err(RuntimeError::InvalidNumber { radix: 10, text: text });
+                            }
+                        }
+                        self.stack.push(StackVal::Bool(true));
+                    }
+                    Err(_) => return Err(RuntimeError::ReadFailed),
+                }
+                self.pc += 1;
+            }
+            &Instr::ReadAll => {
+                let mut input = String::new();
+                if self.input.read_to_string(&mut input).is_err() {
+                    return Err(RuntimeError::ReadFailed);
+                }
+                for tok in input.split_whitespace() {
+                    match tok.parse::<RawNumber>() {
+                        Ok(val) => self.stack.push(StackVal::Number(val)),
+                        Err(_) => {
+                            let text = tok.to_owned();
+                            return Err(RuntimeError::InvalidNumber { radix: 10, text: text });
+                        }
+                    }
+                }
+                self.pc += 1;
+            }
+            &Instr::Sleep(_) if self.safe => {
+                return Err(RuntimeError::OperationNotPermitted("sleep"))
+            }
+            // A step limit bounds how long a program runs only if each step is quick.
+            &Instr::Sleep(_) if self.max_steps.is_some() => {
+                return Err(RuntimeError::SleepWithStepLimit)
+            }
+            &Instr::Sleep(ms) => {
+                thread::sleep(Duration::from_millis(ms));
+                self.pc += 1;
+            }
+            &Instr::Exit(_) if self.safe => return Err(RuntimeError::OperationNotPermitted("exit")),
+            &Instr::Exit(code) => return Ok(Some(Outcome::Halt { code: code })),
+            &Instr::Sum => {
+                let total = self.stack.reduce("sum", 0, RawNumber::checked_add)?;
+                self.stack.push(StackVal::Number(total));
+                self.pc += 1;
+            }
+            &Instr::Product => {
+                let total = self.stack.reduce("product", 1, RawNumber::checked_mul)?;
+                self.stack.push(StackVal::Number(total));
+                self.pc += 1;
+            }
+            &Instr::TypeOf => {
+                let tag = self.stack.top()?.type_tag();
+                self.stack.push(StackVal::Number(tag));
+                self.pc += 1;
+            }
+            &Instr::Concat(n) => {
+                let joined = self.stack.concat(n)?;
+                self.stack.push(StackVal::Str(joined));
+                self.pc += 1;
+            }
+            &Instr::Len => {
+                let len = self.stack.pop_string()?.chars().count();
+                self.stack.push(StackVal::Number(len as RawNumber));
+                self.pc += 1;
+            }
+            &Instr::Compare(ref kind) => {
+                let (arg1, arg2) = (self.stack.pop_number()?, self.stack.pop_number()?);
+                self.stack.push(StackVal::Bool(kind.compare(arg2, arg1)));
+                self.pc += 1;
+            }
+            &Instr::LogicalAnd => {
+                let (arg1, arg2) = (self.stack.pop_bool()?, self.stack.pop_bool()?);
+                self.stack.push(StackVal::Bool(arg1 && arg2));
+                self.pc += 1;
+            }
+            &Instr::LogicalOr => {
+                let (arg1, arg2) = (self.stack.pop_bool()?, self.stack.pop_bool()?);
+                self.stack.push(StackVal::Bool(arg1 || arg2));
+                self.pc += 1;
+            }
+            &Instr::Print => {
+                let arg = self.stack.pop()?;
+                println!("{}", arg);
+                self.pc += 1;
+            }
+            &Instr::PrintBase => {
+                let base = self.stack.pop_number()?;
+                if base < 2 || base > 36 {
+                    return Err(RuntimeError::InvalidArgument("base must be from 2 to 36"));
+                }
+                println!("{}", format_radix(self.stack.pop_number()?, base as u32));
+                self.pc += 1;
+            }
+            &Instr::PeekN(depth) => {
+                println!("{}", self.stack.get(depth)?);
+                self.pc += 1;
+            }
+            &Instr::Tee => {
+                println!("{}", self.stack.top()?);
+                self.pc += 1;
+            }
+            &Instr::PrintAll => {
+                let vals: Vec<String> = self.stack.drain().iter().map(|v| v.to_string()).collect();
+                println!("{}", vals.join(" "));
+                self.pc += 1;
+            }
+            &Instr::Hash => {
+                let hash = hash_values(&self.stack.drain());
+                self.stack.push(StackVal::Number(hash as RawNumber));
+                self.pc += 1;
+            }
+            &Instr::Checkpoint => {
+                self.checkpoints.push((self.stack_name.clone(), self.stack.clone()));
+                self.pc += 1;
+            }
+            &Instr::Rollback => match self.checkpoints.pop() {
+                Some((name, stack)) => {
+                    // The copy goes back where it came from, even if another stack is now active.
+                    *named_stack(&mut self.stack, &self.stack_name, &mut self.stacks, &name) =
+                        stack;
+                    self.pc += 1;
+                }
+                None => return Err(RuntimeError::RollbackWithoutCheckpoint),
+            },
+            &Instr::Shuffle => {
+                self.stack.shuffle(&mut self.rng);
+                self.pc += 1;
+            }
+            &Instr::Pop => {
+                let _ = self.stack.pop()?;
+                self.pc += 1;
+            }
+            &Instr::Nop => self.pc += 1,
+            &Instr::ExpectStack(ref expected) => {
+                if self.debug {
+                    let expected: Vec<StackVal> =
+                        expected.iter().map(|n| StackVal::Number(*n)).collect();
+                    if self.stack.contents() != &expected[..] {
+                        let found = self.stack.contents().to_vec();
+                        return Err(RuntimeError::ExpectStack { expected: expected, found: found });
+                    }
+                }
+                self.pc += 1;
+            }
+            &Instr::Store(ref name) => {
+                self.vars.insert(name.clone(), self.stack.pop()?);
+                self.pc += 1;
+            }
+            &Instr::Load(ref name) => {
+                match self.vars.get(name) {
+                    Some(val) => self.stack.push(val.clone()),
+                    None => return Err(RuntimeError::UndefinedVariable(name.clone())),
+                }
+                self.pc += 1;
+            }
+            &Instr::IncVar(ref name) | &Instr::DecVar(ref name) => {
+                let delta = if let &Instr::IncVar(..) = instr { 1 } else { -1 };
+                match self.vars.get_mut(name) {
+                    Some(&mut StackVal::Number(ref mut val)) => match val.checked_add(delta) {
+                        Some(new) => *val = new,
+                        None => return Err(RuntimeError::ArithmeticOverflow),
+                    },
+                    Some(val) => return Err(type_mismatch("number", val)),
+                    None => return Err(RuntimeError::UndefinedVariable(name.clone())),
+                }
+                self.pc += 1;
+            }
+            &Instr::Watch(ref name) => {
+                match self.vars.get(name) {
+                    Some(val) => eprintln!("{}: watch {} = {}", self.pc, name, val),
+                    None => return Err(RuntimeError::UndefinedVariable(name.clone())),
+                }
+                self.pc += 1;
+            }
+            &Instr::UseStack(ref name) => {
+                if *name != self.stack_name {
+                    let stack = self.stacks.remove(name).unwrap_or_else(Stack::new);
+                    let prev = mem::replace(&mut self.stack, stack);
+                    let prev_name = mem::replace(&mut self.stack_name, name.clone());
+                    self.stacks.insert(prev_name, prev);
+                }
+                self.pc += 1;
+            }
+            &Instr::MoveTop(ref src, ref dst) => {
+                let val =
+                    named_stack(&mut self.stack, &self.stack_name, &mut self.stacks, src).pop()?;
+                named_stack(&mut self.stack, &self.stack_name, &mut self.stacks, dst).push(val);
+                self.pc += 1;
+            }
+            &Instr::MemLoad => {
+                let addr = memory_addr(self.stack.pop_number()?)?;
+                let val = self.memory.get(addr).cloned().unwrap_or(0);
+                self.stack.push(StackVal::Number(val));
+                self.pc += 1;
+            }
+            &Instr::MemStore => {
+                let addr = memory_addr(self.stack.pop_number()?)?;
+                let val = self.stack.pop_number()?;
+                if addr >= self.memory.len() {
+                    self.memory.resize(addr + 1, 0);
+                }
+                self.memory[addr] = val;
+                self.pc += 1;
+            }
+            &Instr::MemClear => {
+                self.memory.clear();
+                self.pc += 1;
+            }
+            &Instr::MemDump => {
+                for (addr, &val) in self.memory.iter().enumerate() {
+                    if val != 0 {
+                        eprintln!("{}: {}", addr, val);
+                    }
+                }
+                self.pc += 1;
+            }
+            &Instr::Jump(ref target) => {
+                if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                    self.pc = addr;
+                } else {
+                    return Err(target.undefined(&self.labels));
+                }
+            }
+            &Instr::JumpRel(offset) => match instr.rel_target(self.pc, self.program.len()) {
+                Some(addr) => self.pc = addr,
+                None => {
+                    let addr = self.pc as i64 + offset as i64;
+                    return Err(RuntimeError::JumpOutOfBounds(addr));
+                }
+            },
+            &Instr::Call(ref target) | &Instr::CallN(ref target, _) => {
+                if let Some(max) = self.max_call_depth {
+                    if self.calls.len() >= max {
+                        return Err(RuntimeError::CallStackOverflow);
+                    }
+                }
+                let base = match instr {
+                    &Instr::CallN(_, argc) => match self.stack.depth().checked_sub(argc) {
+                        Some(base) => Some(base),
+                        None => return Err(RuntimeError::StackUnderflow),
+                    },
+                    _ => None,
+                };
+                if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                    self.calls.push(Frame { ret_addr: self.pc + 1, base: base });
+                    self.pc = addr;
+                } else {
+                    return Err(target.undefined(&self.labels));
+                }
+            }
+            &Instr::Ret => match self.calls.pop() {
+                Some(frame) => {
+                    // A `try` entered inside the call can't catch errors in the caller.
+                    let calls = self.calls.len();
+                    self.handlers.retain(|h| h.calls <= calls);
+                    self.pc = frame.ret_addr;
+                }
+                None => return Err(RuntimeError::ReturnWithoutCall),
+            },
+            &Instr::RetN(retc) => match self.calls.pop() {
+                Some(frame) => {
+                    if let Some(base) = frame.base {
+                        if self.stack.depth() != base + retc {
+                            return Err(RuntimeError::CallContract {
+                                expected: base + retc,
+                                found: self.stack.depth(),
+                            });
+                        }
+                    }
+                    let calls = self.calls.len();
+                    self.handlers.retain(|h| h.calls <= calls);
+                    self.pc = frame.ret_addr;
+                }
+                None => return Err(RuntimeError::ReturnWithoutCall),
+            },
+            &Instr::PushPc => {
+                self.stack.push(StackVal::Number(self.pc as RawNumber));
+                self.pc += 1;
+            }
+            &Instr::Try(ref target) => {
+                if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                    self.handlers.push(Handler { addr: addr, calls: self.calls.len() });
+                    self.pc += 1;
+                } else {
+                    return Err(target.undefined(&self.labels));
+                }
+            }
+            &Instr::EndTry => match self.handlers.pop() {
+                Some(_) => self.pc += 1,
+                None => return Err(RuntimeError::EndTryWithoutTry),
+            },
+            // XXX generalise binary operations to reduce duplication
+            &Instr::JumpNotEqual(cmp_val, ref target) => {
+                let val = self.stack.pop_comparable()?;
+                if val != cmp_val {
+                    if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                        self.pc = addr;
+                    } else {
+                        return Err(target.undefined(&self.labels));
+                    }
+                } else {
+                    self.pc += 1;
+                }
+            }
+            &Instr::JumpEqual(cmp_val, ref target) => {
+                let val = self.stack.pop_comparable()?;
+                if val == cmp_val {
+                    if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                        self.pc = addr;
+                    } else {
+                        return Err(target.undefined(&self.labels));
+                    }
+                } else {
+                    self.pc += 1;
+                }
+            }
+            &Instr::JumpNegative(ref target) => {
+                let val = self.stack.pop_number()?;
+                if val < 0 {
+                    if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                        self.pc = addr;
+                    } else {
+                        return Err(target.undefined(&self.labels));
+                    }
+                } else {
+                    self.pc += 1;
+                }
+            }
+            &Instr::JumpZero(ref target) => {
+                let val = self.stack.pop_comparable()?;
+                if val == 0 {
+                    if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                        self.pc = addr;
+                    } else {
+                        return Err(target.undefined(&self.labels));
+                    }
+                } else {
+                    self.pc += 1;
+                }
+            }
+            &Instr::JumpPositive(ref target) => {
+                let val = self.stack.pop_comparable()?;
+                if val > 0 {
+                    if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                        self.pc = addr;
+                    } else {
+                        return Err(target.undefined(&self.labels));
+                    }
+                } else {
+                    self.pc += 1;
+                }
+            }
+            &Instr::JumpIfEmpty(ref target) => {
+                if self.stack.depth() == 0 {
+                    if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                        self.pc = addr;
+                    } else {
+                        return Err(target.undefined(&self.labels));
+                    }
+                } else {
+                    self.pc += 1;
+                }
+            }
+            &Instr::JumpIfShallow(depth, ref target) => {
+                if self.stack.depth() < depth {
+                    if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                        self.pc = addr;
+                    } else {
+                        return Err(target.undefined(&self.labels));
+                    }
+                } else {
+                    self.pc += 1;
+                }
+            }
+            &Instr::Switch(ref cases, ref default) => {
+                let val = self.stack.pop_number()?;
+                let target = match cases.iter().find(|&&(case, _)| case == val) {
+                    Some(&(_, ref target)) => target,
+                    None => default,
+                };
+                if let Some(addr) = target.find_addr(&self.labels, &mut self.label_lookups) {
+                    self.pc = addr;
+                } else {
+                    return Err(target.undefined(&self.labels));
+                }
+            }
+        }
+        if trace_jump {
+            if let Some(ref mut out) = self.trace {
+                if writeln!(out, "{} {} -> {}", from, instr.opcode(), self.pc).is_err() {
+                    return Err(RuntimeError::TraceWrite);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Clone)]
+pub enum Instr {
+    Push(StackVal),
+    Range(RawNumber), // push 0, 1, ..., .0 - 1
+    AddAll(RawNumber), // add .0 to every value on the stack, which must all be numbers
+    PushData(String, Vec<RawNumber>), // push .1, in order. .0 names the `.data` it came from
+    Pop,
+    Nop, // do nothing
+    Sleep(u64), // pause for .0 milliseconds. Not allowed in safe mode or with a step limit
+    ExpectStack(Vec<RawNumber>), // in debug mode, check the stack (bottom first) is exactly .0
+    Store(VarName), // pop a value into the named variable
+    Load(VarName), // push the value of the named variable
+    IncVar(VarName), // add one to the named (numeric) variable
+    DecVar(VarName), // subtract one from the named (numeric) variable
+    Watch(VarName), // print the instruction's address and the variable on stderr
+    UseStack(StackName), // make the named stack the active one
+    MoveTop(StackName, StackName), // pop a value from stack .0 and push it on to stack .1
+    // The memory is `MEMORY_SIZE` numbers, all initially 0, addressed from 0. An address outside
+    // it is an error.
+    MemLoad, // pop an address and push the number stored there
+    MemStore, // pop an address, then a number to store there
+    MemClear, // set every memory cell back to 0
+    MemDump, // print the address and value of each non-zero memory cell on stderr, in order
+    Add,
+    Dup,
+    DupIfNonZero, // duplicate top of stack only if it is non-zero
+    RollUp, // move the bottom element of the stack to the top
+    RollDown, // move the top element of the stack to the bottom
+    Swap2, // exchange the top two pairs of elements: a b c d becomes c d a b
+    Remove(usize), // delete the element .0 places below the top (0 being the top)
+    RotN(usize), // rotate the top .0 elements, moving the deepest of them to the top
+    Sub,
+    RSub, // like sub, but the first value popped minus the second
+    Sqrt, // replace top of stack with its integer square root, rounded down
+    Half, // replace top of stack with half of it, rounded towards zero
+    IsEven, // push whether the number on top of the stack is even, leaving it in place
+    // Convert a float on top of the stack to a number, rounding towards zero. A number is left as
+    // it is. A float outside the range of numbers (or NaN) is an arithmetic overflow.
+    ToInt,
+    // Convert a number on top of the stack to the nearest float. A float is left as it is.
+    ToFloat,
+    Log2, // replace top of stack with its base 2 logarithm, rounded down
+    DivMod, // pop divisor then dividend, push the quotient then the remainder
+    Digits, // push the decimal digits of top of stack, most significant first, then their count
+    Sum, // pop every value on the stack and push their total (0 if there are none)
+    Product, // pop every value on the stack and push their product (1 if there are none)
+    // Pop .0 strings and push them joined into one, the deepest first. `concat 0` pushes "".
+    Concat(usize),
+    Len, // replace a string on top of the stack with its length in characters (not bytes)
+    TypeOf, // push the type tag (see `StackVal::type_tag`) of top of stack, leaving it in place
+    Compare(CmpKind), // pop b then a, push the boolean result of `a <op> b`
+    LogicalAnd, // pop two booleans and push their conjunction
+    LogicalOr, // pop two booleans and push their disjunction
+    Jump(JumpTarget), // unconditionally jump to .0
+    // Unconditionally jump .0 instructions forward (or backward, if negative) from this one.
+    // Jumping to just past the last instruction ends the program.
+    JumpRel(i32),
+    Call(JumpTarget), // jump to .0, to return to the next instruction with `ret`
+    Ret, // return from the innermost active `call`
+    // Like `call`, but .1 values on the stack are arguments. A matching `retn` checks that the
+    // callee replaced them with the number of values it promises to return.
+    CallN(JumpTarget, usize),
+    // Like `ret`, but if the call was a `calln`, check that the stack holds exactly .0 more values
+    // than it did below the call's arguments. After a plain `call`, nothing is checked.
+    RetN(usize),
+    PushPc, // push the address of this instruction
+    // Until the matching `endtry`, or the `ret` from the enclosing `call`, catch runtime errors
+    // by jumping to .0. See `Interp::step`.
+    Try(JumpTarget),
+    EndTry, // end the innermost active `try`
+    // Jump to .1 if top of stack == .0 (or != .0). A boolean on the stack is compared as 0 for
+    // false and 1 for true, so `if` and `while` (which lower to `je 0`) accept comparison results.
+    JumpEqual(RawNumber, JumpTarget),
+    JumpNotEqual(RawNumber, JumpTarget),
+    JumpNegative(JumpTarget), // jump to .0 if top of stack < 0
+    // Jump to .0 if top of stack == 0 (or > 0). As with `je`, a boolean is 0 or 1, so `jzero`
+    // jumps on false and `jpos` on true.
+    JumpZero(JumpTarget),
+    JumpPositive(JumpTarget),
+    JumpIfEmpty(JumpTarget), // jump to .0 if the stack is empty. Nothing is popped
+    JumpIfShallow(usize, JumpTarget), // jump to .1 if the stack depth < .0. Nothing is popped
+    // Pop a number and jump to the label of the case in .0 with that value, or to .1 if none.
+    Switch(Vec<(RawNumber, JumpTarget)>, JumpTarget),
+    Print,
+    Tee, // like print, but leave the value on the stack
+    // Pop a base (2 to 36) then a number, and print the number in that base. See `format_radix`.
+    PrintBase,
+    PeekN(usize), // print the value .0 places below the top (0 being the top), leaving it there
+    PrintAll, // print the whole stack, bottom first, on one line and empty it
+    Hash, // replace the whole stack with a checksum of it. See `hash_values`
+    Shuffle, // randomly reorder the whole stack
+    Checkpoint, // save a copy of the active stack
+    Rollback, // replace a stack with the copy of it most recently saved by `checkpoint`
+    Read(u32), // read a line from stdin and push it as a number in the given radix
+    ReadAll, // read the rest of stdin and push each whitespace separated number on it, in order
+    // Read a line from stdin. If there was one, push it as a number and then true. At the end of
+    // the input, push only false. This suits loops like `readline; je 0 done; ...`.
+    ReadLine,
+    Exit(i32), // stop the program, asking for the given process exit code (0 to 255)
+}
+
+impl Instr {
+    // The source mnemonic for the instruction.
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            &Instr::Push(..) => "push",
+            &Instr::Range(..) => "range",
+            &Instr::AddAll(..) => "addall",
+            &Instr::PushData(..) => "pushdata",
+            &Instr::Pop => "pop",
+            &Instr::Nop => "nop",
+            &Instr::Sleep(..) => "sleep",
+            &Instr::ExpectStack(..) => "expectstack",
+            &Instr::Store(..) => "store",
+            &Instr::Load(..) => "load",
+            &Instr::IncVar(..) => "incvar",
+            &Instr::DecVar(..) => "decvar",
+            &Instr::Watch(..) => "watch",
+            &Instr::UseStack(..) => "usestack",
+            &Instr::MoveTop(..) => "movetop",
+            &Instr::MemLoad => "memload",
+            &Instr::MemStore => "memstore",
+            &Instr::MemClear => "memclear",
+            &Instr::MemDump => "memdump",
+            &Instr::Add => "add",
+            &Instr::Dup => "dup",
+            &Instr::DupIfNonZero => "dupnz",
+            &Instr::RollUp => "rollup",
+            &Instr::RollDown => "rolldown",
+            &Instr::Swap2 => "2swap",
+            &Instr::Remove(..) => "remove",
+            &Instr::RotN(..) => "rotn",
+            &Instr::Sub => "sub",
+            &Instr::RSub => "rsub",
+            &Instr::Sqrt => "sqrt",
+            &Instr::Half => "half",
+            &Instr::IsEven => "iseven",
+            &Instr::ToInt => "toint",
+            &Instr::ToFloat => "tofloat",
+            &Instr::Log2 => "log2",
+            &Instr::DivMod => "divmod",
+            &Instr::Digits => "digits",
+            &Instr::Sum => "sum",
+            &Instr::Product => "product",
+            &Instr::Len => "len",
+            &Instr::Concat(..) => "concat",
+            &Instr::TypeOf => "typeof",
+            &Instr::Compare(ref kind) => kind.opcode(),
+            &Instr::LogicalAnd => "land",
+            &Instr::LogicalOr => "lor",
+            &Instr::Jump(..) => "jmp",
+            &Instr::JumpRel(..) => "jrel",
+            &Instr::Call(..) => "call",
+            &Instr::Ret => "ret",
+            &Instr::Try(..) => "try",
+            &Instr::EndTry => "endtry",
+            &Instr::CallN(..) => "calln",
+            &Instr::RetN(..) => "retn",
+            &Instr::PushPc => "pushpc",
+            &Instr::JumpEqual(..) => "je",
+            &Instr::JumpNotEqual(..) => "jne",
+            &Instr::JumpNegative(..) => "jneg",
+            &Instr::JumpZero(..) => "jzero",
+            &Instr::JumpPositive(..) => "jpos",
+            &Instr::JumpIfEmpty(..) => "jifempty",
+            &Instr::JumpIfShallow(..) => "jifdepth",
+            &Instr::Switch(..) => "switch",
+            &Instr::Print => "print",
+            &Instr::Tee => "tee",
+            &Instr::PrintBase => "printbase",
+            &Instr::PeekN(..) => "peekn",
+            &Instr::PrintAll => "printall",
+            &Instr::Hash => "hash",
+            &Instr::Shuffle => "shuffle",
+            &Instr::Checkpoint => "checkpoint",
+            &Instr::Rollback => "rollback",
+            &Instr::Read(..) => "read",
+            &Instr::ReadAll => "readall",
+            &Instr::ReadLine => "readline",
+            &Instr::Exit(..) => "exit",
+        }
+    }
+
+    // How many values the instruction pops from, then pushes on to, the active stack, or None if
+    // that depends on values only known at run-time (e.g. `sum` pops the whole stack). Values
+    // which are only inspected, or rearranged in place, don't count.
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        match self {
+            &Instr::Nop
+            | &Instr::Sleep(..)
+            | &Instr::ExpectStack(..)
+            | &Instr::IncVar(..)
+            | &Instr::DecVar(..)
+            | &Instr::Watch(..)
+            | &Instr::RollUp
+            | &Instr::RollDown
+            | &Instr::Swap2
+            | &Instr::RotN(..)
+            | &Instr::Shuffle
+            | &Instr::Checkpoint
+            | &Instr::PeekN(..)
+            | &Instr::AddAll(..)
+            | &Instr::MemClear
+            | &Instr::MemDump
+            | &Instr::Jump(..)
+            | &Instr::JumpRel(..)
+            | &Instr::Call(..)
+            | &Instr::Ret
+            | &Instr::CallN(..)
+            | &Instr::RetN(..)
+            | &Instr::Try(..)
+            | &Instr::EndTry
+            | &Instr::JumpIfEmpty(..)
+            | &Instr::JumpIfShallow(..)
+            | &Instr::Exit(..) => Some((0, 0)),
+            &Instr::Push(..) | &Instr::Load(..) | &Instr::PushPc | &Instr::Read(..) => {
+                Some((0, 1))
+            }
+            &Instr::Range(n) if n >= 0 => Some((0, n as usize)),
+            &Instr::PushData(_, ref vals) => Some((0, vals.len())),
+            &Instr::Pop
+            | &Instr::Store(..)
+            | &Instr::JumpEqual(..)
+            | &Instr::JumpNotEqual(..)
+            | &Instr::JumpNegative(..)
+            | &Instr::JumpZero(..)
+            | &Instr::JumpPositive(..)
+            | &Instr::Switch(..)
+            | &Instr::Print => Some((1, 0)),
+            &Instr::PrintBase => Some((2, 0)),
+            &Instr::MemStore => Some((2, 0)),
+            &Instr::Sqrt
+            | &Instr::Half
+            | &Instr::ToInt
+            | &Instr::ToFloat
+            | &Instr::Log2
+            | &Instr::Len
+            | &Instr::MemLoad
+            | &Instr::Tee => Some((1, 1)),
+            &Instr::Dup | &Instr::TypeOf | &Instr::IsEven => Some((1, 2)),
+            &Instr::Add
+            | &Instr::Sub
+            | &Instr::RSub
+            | &Instr::Compare(..)
+            | &Instr::LogicalAnd
+            | &Instr::LogicalOr => Some((2, 1)),
+            &Instr::DivMod => Some((2, 2)),
+            &Instr::Remove(n) => Some((n + 1, n)),
+            &Instr::Concat(n) => Some((n, 1)),
+            &Instr::Range(..)
+            | &Instr::UseStack(..)
+            | &Instr::MoveTop(..)
+            | &Instr::DupIfNonZero
+            | &Instr::Digits
+            | &Instr::Sum
+            | &Instr::Product
+            | &Instr::PrintAll
+            | &Instr::Hash
+            | &Instr::ReadAll
+            | &Instr::ReadLine
+            | &Instr::Rollback => None,
+        }
+    }
+
+    // The instruction in the source syntax accepted by the parser, with jump targets named from
+    // `labels`. Parsing the result gives back an equivalent instruction, except for these, which
+    // have no source syntax of their own:
+    //
+    //   - `push` of a boolean (only `load_program` can create one)
+    //   - `push` of NaN (likewise)
+    //   - `pushdata`, which needs its `.data` directive
+    //   - jumps to the `$` labels generated for `if`, `while` and `repeat`, which the parser
+    //     reserves
+    pub fn display<'a>(&'a self, labels: &'a LabelInterner) -> InstrDisplay<'a> {
+        InstrDisplay { instr: self, labels: labels }
+    }
+
+    // The labels that the instruction may jump to.
+    fn jump_targets(&self) -> Vec<&JumpTarget> {
+        match self {
+            &Instr::Jump(ref target)
+            | &Instr::Call(ref target)
+            | &Instr::CallN(ref target, _)
+            | &Instr::Try(ref target)
+            | &Instr::JumpEqual(_, ref target)
+            | &Instr::JumpNotEqual(_, ref target)
+            | &Instr::JumpNegative(ref target)
+            | &Instr::JumpZero(ref target)
+            | &Instr::JumpPositive(ref target)
+            | &Instr::JumpIfEmpty(ref target)
+            | &Instr::JumpIfShallow(_, ref target) => vec![target],
+            &Instr::Switch(ref cases, ref default) => {
+                cases.iter().map(|&(_, ref target)| target).chain(Some(default)).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn jump_targets_mut(&mut self) -> Vec<&mut JumpTarget> {
+        match self {
+            &mut Instr::Jump(ref mut target)
+            | &mut Instr::Call(ref mut target)
+            | &mut Instr::CallN(ref mut target, _)
+            | &mut Instr::Try(ref mut target)
+            | &mut Instr::JumpEqual(_, ref mut target)
+            | &mut Instr::JumpNotEqual(_, ref mut target)
+            | &mut Instr::JumpNegative(ref mut target)
+            | &mut Instr::JumpZero(ref mut target)
+            | &mut Instr::JumpPositive(ref mut target)
+            | &mut Instr::JumpIfEmpty(ref mut target)
+            | &mut Instr::JumpIfShallow(_, ref mut target) => vec![target],
+            &mut Instr::Switch(ref mut cases, ref mut default) => {
+                let cases = cases.iter_mut().map(|&mut (_, ref mut target)| target);
+                cases.chain(Some(default)).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    // The instruction's operands, each as a JSON object. See `Interp::to_json`.
+    fn json_operands(&self, labels: &LabelInterner) -> Vec<String> {
+        let number = |n: RawNumber| format!("{{\"number\": {}}}", n);
+        let name = |s: &str| format!("{{\"name\": {}}}", json_string(s));
+        let label = |t: &JumpTarget| {
+            format!("{{\"label\": {}}}", json_string(labels.name(t.label)))
+        };
+        match self {
+            &Instr::Push(ref val) => vec![match val {
+                &StackVal::Number(n) => number(n),
+                &StackVal::Float(x) if x.is_finite() => format!("{{\"float\": {:?}}}", x),
+                &StackVal::Float(_) => "{\"float\": null}".to_owned(),
+                &StackVal::Bool(b) => format!("{{\"bool\": {}}}", b),
+                &StackVal::Str(ref s) => format!("{{\"string\": {}}}", json_string(s)),
+            }],
+            &Instr::Range(n) | &Instr::AddAll(n) => vec![number(n)],
+            &Instr::JumpEqual(n, ref target) | &Instr::JumpNotEqual(n, ref target) => {
+                vec![number(n), label(target)]
+            }
+            &Instr::ExpectStack(ref vals) => vals.iter().map(|&n| number(n)).collect(),
+            &Instr::Store(ref var)
+            | &Instr::Load(ref var)
+            | &Instr::IncVar(ref var)
+            | &Instr::DecVar(ref var)
+            | &Instr::Watch(ref var)
+            | &Instr::UseStack(ref var)
+            | &Instr::PushData(ref var, _) => vec![name(var)],
+            &Instr::MoveTop(ref src, ref dst) => vec![name(src), name(dst)],
+            &Instr::Remove(n)
+            | &Instr::RotN(n)
+            | &Instr::RetN(n)
+            | &Instr::PeekN(n)
+            | &Instr::Concat(n) => vec![number(n as RawNumber)],
+            &Instr::CallN(ref target, n) => vec![label(target), number(n as RawNumber)],
+            &Instr::JumpIfShallow(n, ref target) => vec![number(n as RawNumber), label(target)],
+            &Instr::Switch(ref cases, ref default) => {
+                let mut rv: Vec<String> = cases
+                    .iter()
+                    .map(|&(val, ref target)| {
+                        let name = json_string(labels.name(target.label));
+                        format!("{{\"case\": {}, \"label\": {}}}", val, name)
+                    })
+                    .collect();
+                rv.push(label(default));
+                rv
+            }
+            &Instr::Read(radix) => vec![number(radix as RawNumber)],
+            &Instr::Sleep(ms) => vec![number(ms as RawNumber)],
+            &Instr::JumpRel(offset) => vec![number(offset as RawNumber)],
+            &Instr::Exit(code) => vec![number(code as RawNumber)],
+            _ => self.jump_targets().into_iter().map(label).collect(),
+        }
+    }
+
+    // For `jrel` at `addr`, in a program of `len` instructions, the address it jumps to. None
+    // if that is out of bounds, or this isn't a `jrel`.
+    fn rel_target(&self, addr: usize, len: usize) -> Option<usize> {
+        match self {
+            &Instr::JumpRel(offset) => {
+                let target = addr as i64 + offset as i64;
+                if target >= 0 && target <= len as i64 { Some(target as usize) } else { None }
+            }
+            _ => None,
+        }
+    }
+
+    // Whether the instruction may transfer control other than to the following instruction.
+    fn may_jump(&self) -> bool {
+        match self {
+            &Instr::Ret | &Instr::RetN(..) | &Instr::JumpRel(..) => true,
+            _ => !self.jump_targets().is_empty(),
+        }
+    }
+
+    // Whether execution may continue with the following instruction.
+    fn falls_through(&self) -> bool {
+        match self {
+            &Instr::Jump(..)
+            | &Instr::JumpRel(..)
+            | &Instr::Ret
+            | &Instr::RetN(..)
+            | &Instr::Switch(..)
+            | &Instr::Exit(..) => false,
+            _ => true,
+        }
+    }
+}
+
+// An instruction formatted as source. See `Instr::display`.
+pub struct InstrDisplay<'a> {
+    instr: &'a Instr,
+    labels: &'a LabelInterner,
+}
+
+impl<'a> fmt::Display for InstrDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = |t: &JumpTarget| self.labels.name(t.label);
+        write!(f, "{}", self.instr.opcode())?;
+        match self.instr {
+            &Instr::Push(ref val) => match val {
+                &StackVal::Str(ref s) => write!(f, " {}", source_string(s)),
+                &StackVal::Float(x) => write!(f, " {}", source_float(x)),
+                val => write!(f, " {}", val),
+            },
+            &Instr::Range(n) | &Instr::AddAll(n) => write!(f, " {}", n),
+            &Instr::PushData(ref name, _)
+            | &Instr::Store(ref name)
+            | &Instr::Load(ref name)
+            | &Instr::IncVar(ref name)
+            | &Instr::DecVar(ref name)
+            | &Instr::Watch(ref name)
+            | &Instr::UseStack(ref name) => write!(f, " {}", name),
+            &Instr::ExpectStack(ref vals) => {
+                for val in vals {
+                    write!(f, " {}", val)?;
+                }
+                Ok(())
+            }
+            &Instr::MoveTop(ref src, ref dst) => write!(f, " {} {}", src, dst),
+            &Instr::Remove(n)
+            | &Instr::RotN(n)
+            | &Instr::RetN(n)
+            | &Instr::PeekN(n)
+            | &Instr::Concat(n) => write!(f, " {}", n),
+            &Instr::JumpRel(offset) => write!(f, " {}", offset),
+            &Instr::CallN(ref target, argc) => write!(f, " {} {}", label(target), argc),
+            &Instr::JumpEqual(n, ref target) | &Instr::JumpNotEqual(n, ref target) => {
+                write!(f, " {} {}", n, label(target))
+            }
+            &Instr::JumpIfShallow(n, ref target) => write!(f, " {} {}", n, label(target)),
+            &Instr::Switch(ref cases, ref default) => {
+                for &(val, ref target) in cases {
+                    write!(f, " {}:{}", val, label(target))?;
+                }
+                write!(f, " {}", label(default))
+            }
+            &Instr::Read(radix) => write!(f, " {}", radix),
+            &Instr::Sleep(ms) => write!(f, " {}", ms),
+            &Instr::Exit(code) => write!(f, " {}", code),
+            instr => {
+                for target in instr.jump_targets() {
+                    write!(f, " {}", label(target))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// `s` as a string literal that `Interp::parse_string` accepts.
+fn source_string(s: &str) -> String {
+    let mut rv = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => rv.push_str("\\\""),
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\t' => rv.push_str("\\t"),
+            c => rv.push(c),
+        }
+    }
+    rv.push('"');
+    rv
+}
+
+// `x` as a float literal that the parser accepts, which must contain a `.`. Infinities are
+// written as a literal too large to represent.
+fn source_float(x: f64) -> String {
+    if x.is_infinite() {
+        return if x > 0.0 { "1.0e999".to_owned() } else { "-1.0e999".to_owned() };
+    }
+    // `{:?}` gives the shortest representation that reads back as `x`, but may leave out the
+    // `.`, e.g. `1e20`.
+    let rv = format!("{:?}", x);
+    match rv.find('e') {
+        Some(i) if !rv.contains('.') => format!("{}.0{}", &rv[..i], &rv[i..]),
+        _ => rv,
+    }
+}
+
+#[derive(Clone)]
+pub enum CmpKind {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CmpKind {
+    fn compare(&self, a: RawNumber, b: RawNumber) -> bool {
+        match self {
+            &CmpKind::Lt => a < b,
+            &CmpKind::Le => a <= b,
+            &CmpKind::Gt => a > b,
+            &CmpKind::Ge => a >= b,
+            &CmpKind::Eq => a == b,
+            &CmpKind::Ne => a != b,
+        }
+    }
+
+    fn opcode(&self) -> &'static str {
+        match self {
+            &CmpKind::Lt => "lt",
+            &CmpKind::Le => "le",
+            &CmpKind::Gt => "gt",
+            &CmpKind::Ge => "ge",
+            &CmpKind::Eq => "eq",
+            &CmpKind::Ne => "ne",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum ParsedLine {
+    Label(LabelName),
+    Instr(Instr),
+    Data(String, String), // make the numbers in file .1 available to `pushdata .0`
+    PushData(String),
+    If,    // pop top of stack and skip to the matching else/endif if it is zero
+    Else,
+    EndIf,
+    // pop top of stack and skip past the matching endwhile if it is zero. The body is expected
+    // to leave the next condition on the stack before reaching endwhile.
+    While,
+    EndWhile,
+    // Run the body up to the matching endrepeat .1 times. Variable .0 counts down the iterations
+    // left, so the body sees .1 - 1 first and 0 last.
+    Repeat(VarName, RawNumber),
+    EndRepeat,
+}
+
+// Maps label names to small integer IDs. Jumps refer to labels by ID, so that finding the
+// target of a jump is a vector index rather than a string hash and comparison.
+pub struct LabelInterner {
+    ids: HashMap<LabelName, LabelId>,
+    names: Vec<LabelName>,
+    addrs: Vec<Option<usize>>, // the address of each label, if it has been defined
+}
+
+impl LabelInterner {
+    pub fn new() -> Self {
+        LabelInterner {
+            ids: HashMap::new(),
+            names: Vec::new(),
+            addrs: Vec::new(),
+        }
+    }
+
+    // Returns the ID of `name`, allocating a new one if `name` hasn't been seen before.
+    pub fn intern(&mut self, name: &str) -> LabelId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.names.len();
+        self.ids.insert(name.to_owned(), id);
+        self.names.push(name.to_owned());
+        self.addrs.push(None);
+        id
+    }
+
+    // Make `name` refer to the instruction at `addr`. Returns false if it was already defined.
+    pub fn define(&mut self, name: &str, addr: usize) -> bool {
+        let id = self.intern(name);
+        if self.addrs[id].is_some() {
+            return false;
+        }
+        self.addrs[id] = Some(addr);
+        true
+    }
+
+    pub fn name(&self, id: LabelId) -> &str {
+        &self.names[id]
+    }
+
+    // The ID of `name`, or None if it has never been seen.
+    pub fn lookup(&self, name: &str) -> Option<LabelId> {
+        self.ids.get(name).cloned()
+    }
+
+    // The address that a label refers to, or None if the label is undefined.
+    pub fn address(&self, id: LabelId) -> Option<usize> {
+        self.addrs[id]
+    }
+
+    // The names of the labels defined at `addr`.
+    pub fn names_at(&self, addr: usize) -> Vec<&str> {
+        self.names
+            .iter()
+            .zip(&self.addrs)
+            .filter(|&(_, &label_addr)| label_addr == Some(addr))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    // A jump target referring to label `name`.
+    pub fn target(&mut self, name: &str) -> JumpTarget {
+        JumpTarget::new(self.intern(name))
+    }
+}
+
+// The destination of a jump. Once the program is linked, `addr` caches the address of `label`.
+#[derive(Clone)]
+pub struct JumpTarget {
+    pub label: LabelId,
+    pub addr: Option<usize>,
+}
+
+impl JumpTarget {
+    pub fn new(label: LabelId) -> Self {
+        JumpTarget { label: label, addr: None }
+    }
+
+    fn resolve(&self, labels: &LabelInterner) -> Option<usize> {
+        self.addr.or_else(|| labels.address(self.label))
+    }
+
+    // Like `resolve`, for taking the jump: if the label has to be looked up (because the
+    // program isn't linked), `lookups` is incremented.
+    fn find_addr(&self, labels: &LabelInterner, lookups: &mut u64) -> Option<usize> {
+        if self.addr.is_some() {
+            return self.addr;
+        }
+        *lookups += 1;
+        labels.address(self.label)
+    }
+
+    // The error for taking this jump when its label has no address.
+    fn undefined(&self, labels: &LabelInterner) -> RuntimeError {
+        RuntimeError::UndefinedLabel(labels.name(self.label).to_owned())
+    }
+}
+
+// The tokens (opcode and operands) of a source line, consumed left to right.
+struct Operands<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+impl<'a> Operands<'a> {
+    fn new(line: &'a str) -> Result<Self, String> {
+        // Operands may be separated by whitespace and/or commas, e.g. `je 0, loop`. A string
+        // literal is a single token, whatever it contains.
+        let is_sep = |c: char| c.is_whitespace() || c == ',';
+        let mut tokens = Vec::new();
+        let mut rest = line.trim_start_matches(is_sep);
+        while !rest.is_empty() {
+            let len = if rest.starts_with('"') {
+                Self::quoted_len(rest)?
+            } else {
+                rest.find(is_sep).unwrap_or(rest.len())
+            };
+            tokens.push(&rest[..len]);
+            rest = rest[len..].trim_start_matches(is_sep);
+        }
+        Ok(Operands { tokens: tokens, pos: 0 })
+    }
+
+    // The length in bytes of the string literal at the start of `s`, including its quotes.
+    fn quoted_len(s: &str) -> Result<usize, String> {
+        let mut escaped = false;
+        for (i, c) in s.char_indices().skip(1) {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                return Ok(i + 1);
+            }
+        }
+        Err("unterminated string".to_owned())
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        self.next_optional().ok_or_else(|| "too few arguments".to_owned())
+    }
+
+    fn next_optional(&mut self) -> Option<&'a str> {
+        let tok = self.tokens.get(self.pos).cloned();
+        if tok.is_some() {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.tokens.len()
+    }
+
+    // Check that there are no operands left over.
+    fn finish(&self) -> Result<(), String> {
+        match self.tokens.get(self.pos) {
+            Some(tok) => Err(format!("too many operands, starting at '{}'", tok)),
+            None => Ok(()),
+        }
+    }
+}
+
+// The program being built by `Interp::parse`.
+struct ParseState {
+    program: Program,
+    labels: LabelInterner,
+    spans: Vec<Span>,
+    // Structured control flow is lowered to jumps to auto-generated labels. `blocks` tracks
+    // the currently open blocks, innermost last, with the line each was opened on.
+    blocks: Vec<(Block, usize)>,
+    next_block_id: usize,
+    data: HashMap<String, Vec<RawNumber>>, // the numbers loaded by each `.data` directive
+    safe: bool, // whether directives that access the filesystem are forbidden
+}
+
+// An open structured control flow block. The field is the block's unique ID.
+enum Block {
+    If(usize),
+    Else(usize),
+    While(usize),
+    Repeat(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StackVal {
+    Number(RawNumber),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl StackVal {
+    // A number identifying the value's type, as pushed by `typeof`: 0 for a number, 1 for a
+    // float, 2 for a boolean and 3 for a string.
+    pub fn type_tag(&self) -> RawNumber {
+        match self {
+            &StackVal::Number(..) => 0,
+            &StackVal::Float(..) => 1,
+            &StackVal::Bool(..) => 2,
+            &StackVal::Str(..) => 3,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            &StackVal::Number(..) => "number",
+            &StackVal::Float(..) => "float",
+            &StackVal::Bool(..) => "boolean",
+            &StackVal::Str(..) => "string",
+        }
+    }
+}
+
+impl fmt::Display for StackVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &StackVal::Number(val) => write!(f, "{}", val),
+            // Debug formatting keeps the decimal point, so that 2.0 isn't shown as 2.
+            &StackVal::Float(val) => write!(f, "{:?}", val),
+            &StackVal::Bool(val) => write!(f, "{}", val),
+            &StackVal::Str(ref val) => write!(f, "{}", val),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Stack {
+    stack: Vec<StackVal>,
+}
+
+impl Stack {
+    fn new() -> Self {
+        Stack { stack: vec![] }
+    }
+
+    fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    // The values on the stack, bottom first.
+    fn contents(&self) -> &[StackVal] {
+        &self.stack
+    }
+
+    fn push(&mut self, val: StackVal) {
+        self.stack.push(val);
+    }
+
+    // Remove and return every value on the stack, bottom first.
+    fn drain(&mut self) -> Vec<StackVal> {
+        mem::replace(&mut self.stack, vec![])
+    }
+
+    // Rotate the whole stack so that the bottom element becomes the top.
+    fn roll_up(&mut self) {
+        if !self.stack.is_empty() {
+            let bottom = self.stack.remove(0);
+            self.stack.push(bottom);
+        }
+    }
+
+    // Rotate the whole stack so that the top element becomes the bottom.
+    fn roll_down(&mut self) {
+        if let Some(top) = self.stack.pop() {
+            self.stack.insert(0, top);
+        }
+    }
+
+    // Pop every value, which must be numbers, combining them (top first) with `op`, starting
+    // from `identity`. `name` is the reduction's opcode, for the error if `op` overflows.
+    fn reduce(
+        &mut self,
+        name: &'static str,
+        identity: RawNumber,
+        op: fn(RawNumber, RawNumber) -> Option<RawNumber>,
+    ) -> Result<RawNumber, RuntimeError> {
+        let mut total = identity;
+        while self.depth() > 0 {
+            let val = self.pop_number()?;
+            match op(total, val) {
+                Some(next) => total = next,
+                None => {
+                    return Err(RuntimeError::ReductionOverflow {
+                        op: name,
+                        partial: total,
+                        operand: val,
+                    })
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    // Replace every value, which must be numbers, with the result of `f` on it. If any value
+    // isn't a number, or `f` overflows (returning None), the stack is left unchanged.
+    fn map_numbers<F>(&mut self, f: F) -> Result<(), RuntimeError>
+    where
+        F: Fn(RawNumber) -> Option<RawNumber>,
+    {
+        let mut rv = Vec::with_capacity(self.stack.len());
+        for val in &self.stack {
+            match val {
+                &StackVal::Number(n) => match f(n) {
+                    Some(new) => rv.push(StackVal::Number(new)),
+                    None => return Err(RuntimeError::ArithmeticOverflow),
+                },
+                val => return Err(type_mismatch("number", val)),
+            }
+        }
+        self.stack = rv;
+        Ok(())
+    }
+
+    // Pop the top `n` values, which must be strings, and return them joined together, the
+    // deepest first. If there aren't `n` values, or any isn't a string, the stack is left
+    // unchanged.
+    fn concat(&mut self, n: usize) -> Result<String, RuntimeError> {
+        if n > self.stack.len() {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        let start = self.stack.len() - n;
+        let mut rv = String::new();
+        for val in &self.stack[start..] {
+            match val {
+                &StackVal::Str(ref s) => rv.push_str(s),
+                val => return Err(type_mismatch("string", val)),
+            }
+        }
+        self.stack.truncate(start);
+        Ok(rv)
+    }
+
+    // Randomly permute the stack (by Fisher-Yates).
+    fn shuffle(&mut self, rng: &mut Rng) {
+        for i in (1..self.stack.len()).rev() {
+            let j = rng.below(i + 1);
+            self.stack.swap(i, j);
+        }
+    }
+
+    // Remove the value `depth` places below the top (0 being the top itself).
+    fn remove(&mut self, depth: usize) -> Result<StackVal, RuntimeError> {
+        if depth >= self.stack.len() {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        let idx = self.stack.len() - 1 - depth;
+        Ok(self.stack.remove(idx))
+    }
+
+    fn peek(&self) -> Option<&StackVal> {
+        self.stack.last()
+    }
+
+    // The value `depth` places below the top (0 being the top itself), which is left in place.
+    fn get(&self, depth: usize) -> Result<&StackVal, RuntimeError> {
+        if depth >= self.stack.len() {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        Ok(&self.stack[self.stack.len() - 1 - depth])
+    }
+
+    // The value on top of the stack, which is left in place.
+    fn top(&self) -> Result<&StackVal, RuntimeError> {
+        self.peek().ok_or(RuntimeError::StackUnderflow)
+    }
+
+    fn pop(&mut self) -> Result<StackVal, RuntimeError> {
+        self.stack.pop().ok_or(RuntimeError::StackUnderflow)
+    }
+
+    fn pop_number(&mut self) -> Result<RawNumber, RuntimeError> {
+        let item = self.pop()?;
+        let rv = match item {
+            StackVal::Number(val) => val,
+            ref val => return Err(type_mismatch("number", val)),
+        };
+        Ok(rv)
+    }
+
+    // Pop a number, or a boolean converted to 0 (false) or 1 (true).
+    fn pop_comparable(&mut self) -> Result<RawNumber, RuntimeError> {
+        match self.pop()? {
+            StackVal::Number(val) => Ok(val),
+            StackVal::Bool(val) => Ok(val as RawNumber),
+            ref val => Err(type_mismatch("number or boolean", val)),
+        }
+    }
+
+    fn pop_bool(&mut self) -> Result<bool, RuntimeError> {
+        match self.pop()? {
+            StackVal::Bool(val) => Ok(val),
+            ref val => Err(type_mismatch("boolean", val)),
+        }
+    }
+
+    fn pop_string(&mut self) -> Result<String, RuntimeError> {
+        match self.pop()? {
+            StackVal::Str(val) => Ok(val),
+            ref val => Err(type_mismatch("string", val)),
+        }
+    }
+}
+
+// `addr` as an index into the memory, if it is in range.
+fn memory_addr(addr: RawNumber) -> Result<usize, RuntimeError> {
+    if addr < 0 || addr >= MEMORY_SIZE as RawNumber {
+        return Err(RuntimeError::InvalidArgument("memory address out of range"));
+    }
+    Ok(addr as usize)
+}
+
+// Find the stack called `name`, creating it if it doesn't yet exist.
+fn named_stack<'a>(
+    active: &'a mut Stack,
+    active_name: &str,
+    others: &'a mut HashMap<StackName, Stack>,
+    name: &str,
+) -> &'a mut Stack {
+    if name == active_name {
+        active
+    } else {
+        others.entry(name.to_owned()).or_insert_with(Stack::new)
+    }
+}
+
+// The position of an instruction's opcode in the source: a line number (from 1) and a range of
+// byte columns (from 0) within that line. The end column is exclusive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+// A saved copy of an interpreter's execution state. See `Interp::snapshot`.
+#[derive(Clone)]
+pub struct State {
+    stack: Stack,
+    stack_name: StackName,
+    stacks: HashMap<StackName, Stack>,
+    vars: HashMap<VarName, StackVal>,
+    memory: Vec<RawNumber>,
+    calls: Vec<Frame>,
+    checkpoints: Vec<(StackName, Stack)>,
+    handlers: Vec<Handler>,
+    rng: Rng,
+    pc: usize,
+}
+
+// An active `call` or `calln`.
+#[derive(Clone)]
+struct Frame {
+    ret_addr: usize, // where `ret` continues
+    base: Option<usize>, // for `calln`, the stack depth below the arguments
+}
+
+// An active `try`.
+#[derive(Clone)]
+struct Handler {
+    addr: usize, // where execution continues if an error is caught
+    calls: usize, // how many `call`s were active at the `try`
+}
+
+// Execution statistics. See `Interp::stats`.
+#[derive(Clone, Debug)]
+pub struct ExecStats {
+    pub instructions: u64, // the number of instructions executed
+    // The number of jumps which had to look up their label by ID, rather than using the address
+    // cached by `Interp::link`. Once linked, this stays 0.
+    pub label_lookups: u64,
+    // The deepest the active stack has been, before or after any instruction. This can be more
+    // than the deepest entry in `depths`, e.g. if the last instruction pushes.
+    pub max_depth: usize,
+    // A histogram of stack depths: `depths[d]` is the number of instructions which started with
+    // `d` values on the active stack. The counts add up to `instructions`.
+    pub depths: Vec<u64>,
+}
+
+// How a run of a program ended.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Finished, // execution ran off the end of the program
+    Halt { code: i32 }, // the program executed `exit`
+}
+
+// An error in a program detected before it runs.
+#[derive(Debug)]
+pub enum InterpError {
+    UndefinedLabel(LabelName),
+    InvalidEncoding { line: usize }, // the source isn't valid UTF-8 at this (1-based) line
+    Parse { line: usize, msg: String }, // a malformed (1-based) line
+    Io(io::Error), // the source couldn't be read
+    Runtime(RuntimeError), // an expression passed to `Interp::eval_expr` failed
+}
+
+impl fmt::Display for InterpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &InterpError::UndefinedLabel(ref name) => write!(f, "undefined label '{}'", name),
+            &InterpError::InvalidEncoding { line } => {
+                write!(f, "line {}: invalid UTF-8 (is this really a program source file?)", line)
+            }
+            &InterpError::Parse { line, ref msg } => {
+                write!(f, "line {}: parse error: {}", line, msg)
+            }
+            &InterpError::Io(ref e) => write!(f, "failed to read program: {}", e),
+            &InterpError::Runtime(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for InterpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            &InterpError::Io(ref e) => Some(e),
+            // The runtime error's message is already this error's, so it isn't a source too.
+            &InterpError::Runtime(ref e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+// An error raised by a running program.
+#[derive(Debug)]
+pub enum RuntimeError {
+    StackUnderflow,
+    TypeMismatch { expected: &'static str, found: &'static str },
+    ArithmeticOverflow,
+    DivideByZero,
+    // `sum` or `product` (`op`) overflowed combining the result so far, `partial`, with `operand`.
+    ReductionOverflow { op: &'static str, partial: RawNumber, operand: RawNumber },
+    CallStackOverflow, // more `call`s were active than the interpreter's `max_call_depth`
+    StepLimitExceeded, // more instructions were executed than the interpreter's `max_steps`
+    SleepWithStepLimit, // `sleep` was executed while `max_steps` was set
+    JumpOutOfBounds(i64), // `jrel` tried to jump to this address, which isn't in the program
+    OperationNotPermitted(&'static str), // this opcode can't be used in safe mode
+    ReturnWithoutCall,
+    RollbackWithoutCheckpoint,
+    EndTryWithoutTry,
+    // `retn` found a stack depth of `found`, rather than the `expected` depth promised by it and
+    // its `calln`.
+    CallContract { expected: usize, found: usize },
+    InvalidArgument(&'static str), // an operand outside an instruction's domain
+    UndefinedLabel(LabelName),
+    UndefinedVariable(VarName),
+    ExpectStack { expected: Vec<StackVal>, found: Vec<StackVal> },
+    EndOfInput,
+    ReadFailed,
+    InvalidNumber { radix: u32, text: String }, // input that isn't a number
+    TraceWrite,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RuntimeError::StackUnderflow => write!(f, "stack underflow"),
+            &RuntimeError::TypeMismatch { expected, found } => {
+                write!(f, "type error: expected {}, found {}", expected, found)
+            }
+            &RuntimeError::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+            &RuntimeError::DivideByZero => write!(f, "division by zero"),
+            &RuntimeError::ReductionOverflow { op, partial, operand } => {
+                write!(f, "arithmetic overflow in {}: partial result {}, next value {}",
+                       op, partial, operand)
+            }
+            &RuntimeError::CallStackOverflow => write!(f, "call stack overflow"),
+            &RuntimeError::StepLimitExceeded => write!(f, "step limit exceeded"),
+            &RuntimeError::SleepWithStepLimit => {
+                write!(f, "'sleep' is not permitted when a step limit is set")
+            }
+            &RuntimeError::OperationNotPermitted(opcode) => {
+                write!(f, "'{}' is not permitted in safe mode", opcode)
+            }
+            &RuntimeError::JumpOutOfBounds(addr) => {
+                write!(f, "relative jump to address {}, outside the program", addr)
+            }
+            &RuntimeError::ReturnWithoutCall => write!(f, "ret without an active call"),
+            &RuntimeError::RollbackWithoutCheckpoint => write!(f, "rollback without a checkpoint"),
+            &RuntimeError::EndTryWithoutTry => write!(f, "endtry without an active try"),
+            &RuntimeError::CallContract { expected, found } => {
+                write!(f, "call contract broken: expected stack depth {} at retn, found {}",
+                       expected, found)
+            }
+            &RuntimeError::InvalidArgument(msg) => write!(f, "{}", msg),
+            &RuntimeError::UndefinedLabel(ref name) => write!(f, "undefined label '{}'", name),
+            &RuntimeError::UndefinedVariable(ref name) => {
+                write!(f, "undefined variable '{}'", name)
+            }
+            &RuntimeError::ExpectStack { ref expected, ref found } => {
+                let expected: Vec<String> = expected.iter().map(|v| v.to_string()).collect();
+                let found: Vec<String> = found.iter().map(|v| v.to_string()).collect();
+                write!(f, "expectstack failed: expected [{}], found [{}]",
+                       expected.join(", "), found.join(", "))
+            }
+            &RuntimeError::EndOfInput => write!(f, "unexpected end of input"),
+            &RuntimeError::ReadFailed => write!(f, "failed to read input"),
+            &RuntimeError::InvalidNumber { radix, ref text } => {
+                write!(f, "invalid base {} number '{}' in input", radix, text)
+            }
+            &RuntimeError::TraceWrite => write!(f, "failed to write trace"),
+        }
+    }
+}
+
+impl error::Error for RuntimeError {}
+
+impl RuntimeError {
+    // Whether the error is due to a limit set on the interpreter (e.g. `set_max_steps`), rather
+    // than a fault in the program itself.
+    pub fn is_limit(&self) -> bool {
+        match self {
+            &RuntimeError::CallStackOverflow | &RuntimeError::StepLimitExceeded => true,
+            _ => false,
+        }
+    }
+
+    // The number that a caught error pushes (see `Interp::step`), so that the handler can tell
+    // errors apart. Codes stay the same as variants are added.
+    pub fn code(&self) -> RawNumber {
+        match self {
+            &RuntimeError::StackUnderflow => 1,
+            &RuntimeError::TypeMismatch { .. } => 2,
+            &RuntimeError::ArithmeticOverflow | &RuntimeError::ReductionOverflow { .. } => 3,
+            &RuntimeError::DivideByZero => 4,
+            &RuntimeError::CallStackOverflow => 5,
+            &RuntimeError::StepLimitExceeded => 6,
+            &RuntimeError::JumpOutOfBounds(..) => 7,
+            &RuntimeError::OperationNotPermitted(..) => 8,
+            &RuntimeError::ReturnWithoutCall => 9,
+            &RuntimeError::RollbackWithoutCheckpoint => 10,
+            &RuntimeError::CallContract { .. } => 11,
+            &RuntimeError::InvalidArgument(..) => 12,
+            &RuntimeError::UndefinedLabel(..) => 13,
+            &RuntimeError::UndefinedVariable(..) => 14,
+            &RuntimeError::ExpectStack { .. } => 15,
+            &RuntimeError::EndOfInput => 16,
+            &RuntimeError::ReadFailed => 17,
+            &RuntimeError::InvalidNumber { .. } => 18,
+            &RuntimeError::TraceWrite => 19,
+            &RuntimeError::EndTryWithoutTry => 20,
+            &RuntimeError::SleepWithStepLimit => 21,
+        }
+    }
+}
+
+// The error for a value of type `expected` being needed, but `found` being given instead.
+fn type_mismatch(expected: &'static str, found: &StackVal) -> RuntimeError {
+    RuntimeError::TypeMismatch { expected: expected, found: found.type_name() }
+}
+
+// Apply a binary arithmetic operation to `a` and `b`, which must be numbers or floats. Two
+// numbers give a number (via `int_op`, which returns None on overflow). Otherwise the result is
+// a float (via `float_op`), with any number first converted to the nearest float. The
+// conversion is exact for magnitudes up to 2^53; beyond that, precision is lost, e.g. 2^53 + 1
+// becomes 2^53.
+fn arith(
+    a: StackVal,
+    b: StackVal,
+    int_op: fn(RawNumber, RawNumber) -> Option<RawNumber>,
+    float_op: fn(f64, f64) -> f64,
+) -> Result<StackVal, RuntimeError> {
+    let as_float = |val: &StackVal| match val {
+        &StackVal::Number(n) => Ok(n as f64),
+        &StackVal::Float(x) => Ok(x),
+        val => Err(type_mismatch("number or float", val)),
+    };
+    match (a, b) {
+        (StackVal::Number(a), StackVal::Number(b)) => match int_op(a, b) {
+            Some(n) => Ok(StackVal::Number(n)),
+            None => Err(RuntimeError::ArithmeticOverflow),
+        },
+        (a, b) => Ok(StackVal::Float(float_op(as_float(&a)?, as_float(&b)?))),
+    }
+}
+
+// A checksum of `vals`, which is stable across versions. It is the 64-bit FNV-1a hash (offset
+// basis 0xcbf29ce484222325, prime 0x100000001b3) of the following bytes, for each value bottom
+// first:
+//
+//   number: 0x00, then the value as 8 little-endian bytes
+//   bool:   0x01, then 0x00 (false) or 0x01 (true)
+//   string: 0x02, then the UTF-8 length as 8 little-endian bytes, then the UTF-8 bytes
+//   float:  0x03, then the IEEE 754 bits as 8 little-endian bytes
+//
+// The result is the hash's bits as a signed 64-bit number.
+fn hash_values(vals: &[StackVal]) -> i64 {
+    let mut bytes = Vec::new();
+    for val in vals {
+        match val {
+            &StackVal::Number(n) => {
+                bytes.push(0);
+                bytes.extend_from_slice(&(n as i64).to_le_bytes());
+            }
+            &StackVal::Bool(b) => bytes.extend_from_slice(&[1, b as u8]),
+            &StackVal::Str(ref s) => {
+                bytes.push(2);
+                bytes.extend_from_slice(&(s.len() as u64).to_le_bytes());
+                bytes.extend_from_slice(s.as_bytes());
+            }
+            &StackVal::Float(x) => {
+                bytes.push(3);
+                bytes.extend_from_slice(&x.to_bits().to_le_bytes());
+            }
+        }
+    }
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash as i64
+}
+
+// `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut rv = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => rv.push_str("\\\""),
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\t' => rv.push_str("\\t"),
+            c if (c as u32) < 0x20 => rv.push_str(&format!("\\u{:04x}", c as u32)),
+            c => rv.push(c),
+        }
+    }
+    rv.push('"');
+    rv
+}
+
+// A small pseudo-random number generator (SplitMix64). It isn't suitable for cryptography, but it
+// is fast and its output depends only on the seed.
+#[derive(Clone)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // A number in `0..n`. Scaling (rather than taking the remainder) keeps the bias negligible.
+    fn below(&mut self, n: usize) -> usize {
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+}
+
+// `n` in base `radix` (2 to 36), using lowercase letters for digits above 9. A negative number
+// is written as `-` followed by its magnitude, e.g. -10 in base 16 is `-a`, not two's complement.
+fn format_radix(n: RawNumber, radix: u32) -> String {
+    let mut mag = n.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        digits.push(char::from_digit((mag % radix as u64) as u32, radix).unwrap());
+        mag /= radix as u64;
+        if mag == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        digits.push('-');
+    }
+    digits.iter().rev().collect()
+}
+
+// Floor square root of a non-negative number, by Newton's method. Integer arithmetic is used
+// throughout so that large values don't suffer from floating point rounding.
+fn isqrt(n: RawNumber) -> RawNumber {
+    if n < 2 {
+        return n;
+    }
+    // Any initial guess above the root works. This one can't overflow.
+    let mut x = n / 2 + 1;
+    let mut y = (x + n / x) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+// Whether diagnostics are highlighted with terminal color codes. See `set_color`.
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+// Highlight diagnostics (from `fatal` and `highlight`) in red. Off by default, since the escape
+// codes are only wanted when writing to a terminal.
+pub fn set_color(enabled: bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+// `msg`, highlighted if color is enabled.
+pub fn highlight(msg: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[31m{}\x1b[0m", msg)
+    } else {
+        msg.to_owned()
+    }
+}
+
+pub fn fatal(msg: &str) -> ! {
+    eprintln!("{}", highlight(&format!("FATAL: {}", msg)));
+    exit(1);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run `src` to completion and return the final stack.
+    fn run(src: &str) -> Result<Vec<StackVal>, RuntimeError> {
+        let mut interp = Interp::from_reader(src.as_bytes()).unwrap();
+        interp.run_to_result()?;
+        Ok(interp.stack().to_vec())
+    }
+
+    fn numbers(vals: &[RawNumber]) -> Vec<StackVal> {
+        vals.iter().map(|&n| StackVal::Number(n)).collect()
+    }
+
+    #[test]
+    fn parse_i64_bounds() {
+        let stack = run("push 9223372036854775807\npush -9223372036854775808\n").unwrap();
+        assert_eq!(stack, numbers(&[i64::MAX, i64::MIN]));
+        assert_eq!(run("push 9_223_372_036_854_775_807\n").unwrap(), numbers(&[i64::MAX]));
+    }
+
+    #[test]
+    fn parse_past_i64_bounds() {
+        for src in &["push 9223372036854775808\n", "push -9223372036854775809\n"] {
+            match Interp::from_reader(src.as_bytes()) {
+                Err(InterpError::Parse { line: 1, .. }) => (),
+                Err(e) => panic!("{}: wrong error: {}", src, e),
+                Ok(_) => panic!("{}: parsed", src),
+            }
+        }
+    }
+
+    #[test]
+    fn arith_at_i64_bounds() {
+        let max = "push 9223372036854775807\n";
+        let min = "push -9223372036854775808\n";
+        assert_eq!(run(&format!("{}push 1\nsub\n", max)).unwrap(), numbers(&[i64::MAX - 1]));
+        assert_eq!(run(&format!("{}push 1\nadd\n", min)).unwrap(), numbers(&[i64::MIN + 1]));
+        assert_eq!(run(&format!("{}{}add\n", max, min)).unwrap(), numbers(&[-1]));
+        assert_eq!(run(&format!("{}push -1\nsub\n", min)).unwrap(), numbers(&[i64::MIN + 1]));
+    }
+
+    fn add(a: StackVal, b: StackVal) -> StackVal {
+        arith(a, b, RawNumber::checked_add, |a, b| a + b).unwrap()
+    }
+
+    #[test]
+    fn arith_promotion() {
+        use StackVal::{Float, Number};
+        assert_eq!(add(Number(2), Number(3)), Number(5));
+        assert_eq!(add(Number(2), Float(0.5)), Float(2.5));
+        assert_eq!(add(Float(0.5), Number(2)), Float(2.5));
+        assert_eq!(add(Float(0.5), Float(0.25)), Float(0.75));
+        assert_eq!(run("push 2\npush 0.5\nsub\n").unwrap(), vec![Float(1.5)]);
+        assert_eq!(run("push 0.5\npush 2\nsub\n").unwrap(), vec![Float(-1.5)]);
+        assert_eq!(run("push 2\npush 3\nsub\n").unwrap(), vec![Number(-1)]);
+        assert_eq!(run("push 0.5\npush 0.25\nsub\n").unwrap(), vec![Float(0.25)]);
+    }
+
+    #[test]
+    fn arith_rejects_non_numbers() {
+        let err = arith(StackVal::Bool(true), StackVal::Float(1.0), RawNumber::checked_add,
+                        |a, b| a + b);
+        match err {
+            Err(RuntimeError::TypeMismatch { .. }) => (),
+            _ => panic!("expected a type mismatch"),
+        }
+    }
+
+    #[test]
+    fn arith_precision_loss() {
+        use StackVal::{Float, Number};
+        let exact = 1 << 53;
+        assert_eq!(add(Number(exact), Float(0.0)), Float(9007199254740992.0));
+        // 2^53 + 1 has no f64 representation, so it rounds (to even) down to 2^53...
+        assert_eq!(add(Number(exact + 1), Float(0.0)), Float(9007199254740992.0));
+        // ...but stays exact as long as both operands are numbers.
+        assert_eq!(add(Number(exact), Number(1)), Number(exact + 1));
+    }
+
+    #[test]
+    fn reset_and_restore_repeat_shuffles() {
+        let src = "push 1\npush 2\npush 3\npush 4\npush 5\npush 6\nshuffle\n";
+        let mut interp = Interp::from_reader(src.as_bytes()).unwrap();
+        interp.set_seed(7);
+        let start = interp.snapshot();
+        interp.run_to_result().unwrap();
+        let first = interp.stack().to_vec();
+        interp.reset();
+        interp.run_to_result().unwrap();
+        assert_eq!(interp.stack(), &first[..]);
+        interp.restore(start);
+        interp.run_to_result().unwrap();
+        assert_eq!(interp.stack(), &first[..]);
+    }
+
+    #[test]
+    fn fold_constants() {
+        let mut interp = Interp::from_reader("push 1\npush 2\nadd\n".as_bytes()).unwrap();
+        assert_eq!(interp.stack_effects().len(), 3);
+        interp.fold_constants();
+        assert_eq!(interp.stack_effects().len(), 1);
+        interp.run_to_result().unwrap();
+        assert_eq!(interp.stack(), &numbers(&[3])[..]);
+
+        // `pushpc` would see the folded addresses, so nothing is folded.
+        let src = "push 1\npush 2\nadd\npushpc\n";
+        let mut interp = Interp::from_reader(src.as_bytes()).unwrap();
+        interp.fold_constants();
+        interp.run_to_result().unwrap();
+        assert_eq!(interp.stack(), &numbers(&[3, 3])[..]);
+    }
+
+    fn gen_number(rng: &mut Rng) -> RawNumber {
+        match rng.below(4) {
+            0 => 0,
+            1 => RawNumber::MAX,
+            2 => RawNumber::MIN,
+            _ => rng.next_u64() as RawNumber,
+        }
+    }
+
+    fn gen_float(rng: &mut Rng) -> f64 {
+        match rng.below(4) {
+            0 => f64::INFINITY,
+            1 => -0.0,
+            _ => loop {
+                let x = f64::from_bits(rng.next_u64());
+                if !x.is_nan() {
+                    break x;
+                }
+            },
+        }
+    }
+
+    fn gen_string(rng: &mut Rng) -> String {
+        let chars = ['a', 'Z', '0', ' ', ',', ';', ':', '$', '"', '\\', '\n', '\t', 'é'];
+        (0..rng.below(8)).map(|_| chars[rng.below(chars.len())]).collect()
+    }
+
+    fn gen_name(rng: &mut Rng) -> String {
+        format!("v{}", rng.below(100))
+    }
+
+    fn gen_target(rng: &mut Rng, labels: &mut LabelInterner) -> JumpTarget {
+        JumpTarget::new(labels.intern(&format!("l{}", rng.below(100))))
+    }
+
+    // Defines `CASES`, `gen_instr` and `case_name` from a list of `pattern => generator` cases,
+    // then the patterns of variants which can't round-trip (see `Instr::display`). `case_name`
+    // matches on every pattern without a wildcard, so a new variant of `Instr` doesn't compile
+    // until it is given a case here.
+    macro_rules! gen_cases {
+        ($rng:ident, $labels:ident; $($pat:pat => $gen:expr,)* ; $($skip:pat,)*) => {
+            // Every case, by name.
+            const CASES: &[&str] = &[$(stringify!($pat),)*];
+
+            // An instruction for the case called `name`, with random operands.
+            fn gen_instr(name: &str, $rng: &mut Rng, $labels: &mut LabelInterner) -> Instr {
+                $(
+                    if name == stringify!($pat) {
+                        return $gen;
+                    }
+                )*
+                unreachable!()
+            }
+
+            // The name of the case that `instr` belongs to, or None if it can't round-trip.
+            fn case_name(instr: &Instr) -> Option<&'static str> {
+                match instr {
+                    $($pat => Some(stringify!($pat)),)*
+                    $($skip => None,)*
+                }
+            }
+        };
+    }
+
+    gen_cases! {
+        rng, labels;
+        &Instr::Push(StackVal::Number(_)) => Instr::Push(StackVal::Number(gen_number(rng))),
+        &Instr::Push(StackVal::Float(_)) => Instr::Push(StackVal::Float(gen_float(rng))),
+        &Instr::Push(StackVal::Str(_)) => Instr::Push(StackVal::Str(gen_string(rng))),
+        &Instr::Range(..) => Instr::Range(gen_number(rng)),
+        &Instr::AddAll(..) => Instr::AddAll(gen_number(rng)),
+        &Instr::Pop => Instr::Pop,
+        &Instr::Nop => Instr::Nop,
+        &Instr::Sleep(..) => Instr::Sleep(rng.below(1 << 20) as u64),
+        &Instr::ExpectStack(..) => {
+            Instr::ExpectStack((0..rng.below(4)).map(|_| gen_number(rng)).collect())
+        },
+        &Instr::Store(..) => Instr::Store(gen_name(rng)),
+        &Instr::Load(..) => Instr::Load(gen_name(rng)),
+        &Instr::IncVar(..) => Instr::IncVar(gen_name(rng)),
+        &Instr::DecVar(..) => Instr::DecVar(gen_name(rng)),
+        &Instr::Watch(..) => Instr::Watch(gen_name(rng)),
+        &Instr::UseStack(..) => Instr::UseStack(gen_name(rng)),
+        &Instr::MoveTop(..) => Instr::MoveTop(gen_name(rng), gen_name(rng)),
+        &Instr::MemLoad => Instr::MemLoad,
+        &Instr::MemStore => Instr::MemStore,
+        &Instr::MemClear => Instr::MemClear,
+        &Instr::MemDump => Instr::MemDump,
+        &Instr::Add => Instr::Add,
+        &Instr::Dup => Instr::Dup,
+        &Instr::DupIfNonZero => Instr::DupIfNonZero,
+        &Instr::RollUp => Instr::RollUp,
+        &Instr::RollDown => Instr::RollDown,
+        &Instr::Swap2 => Instr::Swap2,
+        &Instr::Remove(..) => Instr::Remove(rng.below(1000)),
+        &Instr::RotN(..) => Instr::RotN(rng.below(1000)),
+        &Instr::Sub => Instr::Sub,
+        &Instr::RSub => Instr::RSub,
+        &Instr::Sqrt => Instr::Sqrt,
+        &Instr::Half => Instr::Half,
+        &Instr::IsEven => Instr::IsEven,
+        &Instr::ToInt => Instr::ToInt,
+        &Instr::ToFloat => Instr::ToFloat,
+        &Instr::Log2 => Instr::Log2,
+        &Instr::DivMod => Instr::DivMod,
+        &Instr::Digits => Instr::Digits,
+        &Instr::Sum => Instr::Sum,
+        &Instr::Product => Instr::Product,
+        &Instr::Concat(..) => Instr::Concat(rng.below(1000)),
+        &Instr::Len => Instr::Len,
+        &Instr::TypeOf => Instr::TypeOf,
+        &Instr::Compare(_) => {
+            let kinds = [CmpKind::Lt, CmpKind::Le, CmpKind::Gt, CmpKind::Ge, CmpKind::Eq,
+                         CmpKind::Ne];
+            Instr::Compare(kinds[rng.below(kinds.len())].clone())
+        },
+        &Instr::LogicalAnd => Instr::LogicalAnd,
+        &Instr::LogicalOr => Instr::LogicalOr,
+        &Instr::Jump(..) => Instr::Jump(gen_target(rng, labels)),
+        &Instr::JumpRel(..) => Instr::JumpRel(rng.next_u64() as i32),
+        &Instr::Call(..) => Instr::Call(gen_target(rng, labels)),
+        &Instr::Ret => Instr::Ret,
+        &Instr::CallN(..) => Instr::CallN(gen_target(rng, labels), rng.below(1000)),
+        &Instr::RetN(..) => Instr::RetN(rng.below(1000)),
+        &Instr::PushPc => Instr::PushPc,
+        &Instr::Try(..) => Instr::Try(gen_target(rng, labels)),
+        &Instr::EndTry => Instr::EndTry,
+        &Instr::JumpEqual(..) => Instr::JumpEqual(gen_number(rng), gen_target(rng, labels)),
+        &Instr::JumpNotEqual(..) => {
+            Instr::JumpNotEqual(gen_number(rng), gen_target(rng, labels))
+        },
+        &Instr::JumpNegative(..) => Instr::JumpNegative(gen_target(rng, labels)),
+        &Instr::JumpZero(..) => Instr::JumpZero(gen_target(rng, labels)),
+        &Instr::JumpPositive(..) => Instr::JumpPositive(gen_target(rng, labels)),
+        &Instr::JumpIfEmpty(..) => Instr::JumpIfEmpty(gen_target(rng, labels)),
+        &Instr::JumpIfShallow(..) => {
+            Instr::JumpIfShallow(rng.below(1000), gen_target(rng, labels))
+        },
+        &Instr::Switch(..) => {
+            let cases = (0..rng.below(4))
+                .map(|_| (gen_number(rng), gen_target(rng, labels)))
+                .collect();
+            Instr::Switch(cases, gen_target(rng, labels))
+        },
+        &Instr::Print => Instr::Print,
+        &Instr::Tee => Instr::Tee,
+        &Instr::PrintBase => Instr::PrintBase,
+        &Instr::PeekN(..) => Instr::PeekN(rng.below(1000)),
+        &Instr::PrintAll => Instr::PrintAll,
+        &Instr::Hash => Instr::Hash,
+        &Instr::Shuffle => Instr::Shuffle,
+        &Instr::Checkpoint => Instr::Checkpoint,
+        &Instr::Rollback => Instr::Rollback,
+        &Instr::Read(..) => Instr::Read(2 + rng.below(35) as u32),
+        &Instr::ReadAll => Instr::ReadAll,
+        &Instr::ReadLine => Instr::ReadLine,
+        &Instr::Exit(..) => Instr::Exit(rng.below(256) as i32),
+        ;
+        &Instr::Push(StackVal::Bool(_)),
+        &Instr::PushData(..),
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let mut rng = Rng::new(0);
+        for _ in 0..100 {
+            for &case in CASES {
+                let mut labels = LabelInterner::new();
+                let instr = gen_instr(case, &mut rng, &mut labels);
+                assert_eq!(case_name(&instr), Some(case));
+                let src = format!("{}\n", instr.display(&labels));
+                let interp = match Interp::from_reader(src.as_bytes()) {
+                    Ok(interp) => interp,
+                    Err(e) => panic!("{:?}: {}", src, e),
+                };
+                let (parsed, parsed_labels) = interp.into_program();
+                assert_eq!(parsed.len(), 1, "{:?}", src);
+                // Compare the operands themselves, not just how they print, in case `display`
+                // drops one which the parser then defaults.
+                assert_eq!(parsed[0].opcode(), instr.opcode(), "{:?}", src);
+                assert_eq!(parsed[0].json_operands(&parsed_labels), instr.json_operands(&labels),
+                           "{:?}", src);
+                assert_eq!(parsed[0].display(&parsed_labels).to_string(), src.trim_end());
+            }
+        }
+    }
+
+    #[test]
+    fn rollback_restores_checkpointed_stack() {
+        let src = "push 1\ncheckpoint\npush 2\nusestack b\npush 9\nrollback\n";
+        let mut interp = Interp::from_reader(src.as_bytes()).unwrap();
+        interp.run_to_result().unwrap();
+        assert_eq!(interp.stack(), &numbers(&[9])[..]);
+        assert_eq!(interp.stacks[DEFAULT_STACK].stack, numbers(&[1]));
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let max = "push 9223372036854775807\n";
+        let min = "push -9223372036854775808\n";
+        let srcs = [
+            format!("{}push 1\nadd\n", max),
+            format!("{}push -1\nadd\n", min),
+            format!("{}push 1\nsub\n", min),
+            format!("{}push -1\nsub\n", max),
+            format!("push 1\n{}rsub\n", min),
+            format!("push -1\n{}rsub\n", max),
+            format!("{}store x\nincvar x\n", max),
+            format!("{}store x\ndecvar x\n", min),
+        ];
+        for src in &srcs {
+            // Folding leaves overflowing arithmetic alone, so it fails the same way either way.
+            for &fold in &[false, true] {
+                let mut interp = Interp::from_reader(src.as_bytes()).unwrap();
+                if fold {
+                    interp.fold_constants();
+                }
+                match interp.run_to_result() {
+                    Err(RuntimeError::ArithmeticOverflow) => (),
+                    Err(e) => panic!("{:?}: wrong error: {}", src, e),
+                    Ok(_) => panic!("{:?}: didn't overflow", src),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn max_depth_includes_last_instruction() {
+        let mut interp = Interp::from_reader("range 5\n".as_bytes()).unwrap();
+        interp.run_to_result().unwrap();
+        let stats = interp.stats();
+        assert_eq!(stats.max_depth, 5);
+        assert_eq!(stats.depths, vec![1]);
+    }
+
+    #[test]
+    fn sleep_with_step_limit() {
+        let mut interp = Interp::from_reader("sleep 9223372036854775807\n".as_bytes()).unwrap();
+        interp.set_max_steps(Some(10));
+        match interp.run_to_result() {
+            Err(RuntimeError::SleepWithStepLimit) => (),
+            _ => panic!("expected sleep to be refused"),
+        }
+    }
+
+    #[test]
+    fn try_ends_on_ret() {
+        let src = "call f\npop\nf:\ntry caught\nret\ncaught:\npush 7\n";
+        match run(src) {
+            Err(RuntimeError::StackUnderflow) => (),
+            r => panic!("expected the caller's error not to be caught: {:?}", r),
+        }
+    }
+}
@@ -1,13 +1,383 @@
 extern crate interp;
-use interp::{Interp, fatal};
+use interp::{DEFAULT_COMMENT_PREFIX, Interp, Outcome, RawNumber, RuntimeError, StackVal};
+use interp::{fatal, highlight, set_color};
 use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, LineWriter};
+use std::process::{ExitCode, exit};
+use std::time::{Duration, Instant};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        fatal("usage: simple-rust-stack-interp <file>");
+const USAGE: &str = "usage: simple-rust-stack-interp [options] <file> [-- <number>...]
+
+If <file> is `-`, the program is read from stdin. Numbers after `--` are pushed on to the stack,
+in order, before the program starts.
+
+options:
+  --ast-json           print the parsed program as JSON and exit without running it
+  --bench <n>          run the program <n> times and report the min/median/max wall time
+  --check              check the program for problems without running it
+  --color, --no-color  always/never highlight errors in color. By default, they are highlighted
+                       if stderr (where errors are written) is a terminal
+  --comment-prefix <s> start line comments with <s> instead of `;`. An empty <s> disables them
+  --count-opcodes      print how many times each opcode appears in the program, most frequent
+                       first, and exit without running it
+  --debug              enable debugging instructions such as `expectstack`
+  --entry <label>      start running the program at <label> rather than its first instruction
+  --emit-dot           print the program's control flow graph in Graphviz DOT format and exit
+                       without running it
+  --explain            print the stack effect of each instruction and exit without running it
+  --fold               fold constant arithmetic (e.g. `push 1 push 2 add`) before running. This
+                       changes instruction addresses, so the pcs in traces, --step and `watch`,
+                       and the counts from --stats and --max-steps, are for the folded program
+  --input <path>       read program input from <path> instead of stdin
+  --instr-limit-exit-code <n>
+                       exit with code <n> (default 124) if a limit such as --max-steps or
+                       --max-call-depth is hit, so that it can be told apart from other errors
+  --max-call-depth <n> fail if more than <n> calls are active at once
+  --max-steps <n>      fail if more than <n> instructions are executed. `sleep` is then an error
+  --no-exit            report an error in the program (a parse, link or runtime error) as
+                       `error: ...` on stderr and return from main, rather than exiting from
+                       inside the interpreter. Problems with the command line or with opening
+                       files still exit at once
+  --no-link            don't resolve labels before running, so that each jump looks up its
+                       label. Undefined labels are then only found if a jump to one is taken
+  --safe               run an untrusted program: it may not read files with `.data`, set the
+                       exit code with `exit`, or pause with `sleep`
+  --seed <n>           seed the random number generator used by `shuffle` with <n>. Without it,
+                       the seed is always 0, so runs are reproducible either way
+  --stats              report how many instructions and label lookups were executed, and how
+                       deep the stack was at each instruction, on stderr
+  --step               single-step through the program, taking commands from stdin. Without
+                       --input, the program itself reads no input
+  --strict             reject programs which can fall through into a label, rather than only
+                       reaching labels by jumping
+  --trace-file <path>  write an execution trace to <path>
+  --trace-jumps        only trace instructions which may jump, showing where execution went.
+                       Requires --trace-file";
+
+// The exit code when the program hits a limit, unless --instr-limit-exit-code says otherwise. It
+// is the one `timeout` uses.
+const DEFAULT_LIMIT_EXIT_CODE: u8 = 124;
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let mut filename = None;
+    let mut trace_file = None;
+    let mut trace_jumps = false;
+    let mut check = false;
+    let mut ast_json = false;
+    let mut emit_dot = false;
+    let mut explain = false;
+    let mut fold = false;
+    let mut count_opcodes = false;
+    let mut debug = false;
+    let mut step = false;
+    let mut strict = false;
+    let mut safe = false;
+    let mut no_exit = false;
+    let mut no_link = false;
+    let mut stats = false;
+    let mut color = None;
+    let mut input_file = None;
+    let mut bench = None;
+    let mut max_call_depth = None;
+    let mut max_steps = None;
+    let mut limit_exit_code = DEFAULT_LIMIT_EXIT_CODE;
+    let mut seed = None;
+    let mut entry = None;
+    let mut comment_prefix = DEFAULT_COMMENT_PREFIX.to_owned();
+    let mut prog_args = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--ast-json" => ast_json = true,
+            "--emit-dot" => emit_dot = true,
+            "--explain" => explain = true,
+            "--fold" => fold = true,
+            "--count-opcodes" => count_opcodes = true,
+            "--debug" => debug = true,
+            "--step" => step = true,
+            "--trace-jumps" => trace_jumps = true,
+            "--strict" => strict = true,
+            "--safe" => safe = true,
+            "--no-exit" => no_exit = true,
+            "--no-link" => no_link = true,
+            "--stats" => stats = true,
+            "--color" => color = Some(true),
+            "--no-color" => color = Some(false),
+            "--bench" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n > 0 => bench = Some(n),
+                _ => fatal(USAGE),
+            },
+            "--max-call-depth" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) => max_call_depth = Some(n),
+                None => fatal(USAGE),
+            },
+            "--max-steps" => match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                Some(n) => max_steps = Some(n),
+                None => fatal(USAGE),
+            },
+            "--instr-limit-exit-code" => match args.next().and_then(|n| n.parse::<u8>().ok()) {
+                Some(n) => limit_exit_code = n,
+                None => fatal(USAGE),
+            },
+            "--seed" => match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                Some(n) => seed = Some(n),
+                None => fatal(USAGE),
+            },
+            "--entry" => match args.next() {
+                Some(label) => entry = Some(label),
+                None => fatal(USAGE),
+            },
+            "--comment-prefix" => match args.next() {
+                Some(prefix) => comment_prefix = prefix,
+                None => fatal(USAGE),
+            },
+            "--input" => match args.next() {
+                Some(path) => input_file = Some(path),
+                None => fatal(USAGE),
+            },
+            "--trace-file" => match args.next() {
+                Some(path) => trace_file = Some(path),
+                None => fatal(USAGE),
+            },
+            "--" => {
+                for arg in args.by_ref() {
+                    match arg.parse::<RawNumber>() {
+                        Ok(val) => prog_args.push(val),
+                        Err(_) => fatal(&format!("program arguments must be numbers: {}", arg)),
+                    }
+                }
+            }
+            _ if filename.is_none() => filename = Some(arg),
+            _ => fatal(USAGE),
+        }
+    }
+
+    let filename = match filename {
+        Some(f) => f,
+        None => fatal(USAGE),
+    };
+    set_color(color.unwrap_or_else(|| io::stderr().is_terminal()));
+    if trace_jumps && trace_file.is_none() {
+        fatal("--trace-jumps requires --trace-file");
+    }
+    if check {
+        exit(run_check(&filename, &comment_prefix, strict, safe));
+    }
+    let reader = open_program(&filename);
+    let parsed = if safe {
+        Interp::from_reader_safe(reader, &comment_prefix)
     } else {
-        let mut interp = Interp::new(&args[1]);
+        Interp::from_reader_with_comment_prefix(reader, &comment_prefix)
+    };
+    let mut interp = match parsed {
+        Ok(interp) => interp,
+        Err(e) => return program_error(&e.to_string(), no_exit),
+    };
+    if ast_json {
+        println!("{}", interp.to_json());
+        return ExitCode::SUCCESS;
+    }
+    if emit_dot {
+        print!("{}", interp.to_dot());
+        return ExitCode::SUCCESS;
+    }
+    if explain {
+        for (addr, opcode, effect) in interp.stack_effects() {
+            match effect {
+                Some((pops, pushes)) => {
+                    println!("{}: {} pops {}, pushes {}", addr, opcode, pops, pushes)
+                }
+                None => println!("{}: {} has a stack effect known only at run-time", addr, opcode),
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+    if count_opcodes {
+        for (opcode, count) in interp.opcode_counts() {
+            println!("{:>8} {}", count, opcode);
+        }
+        return ExitCode::SUCCESS;
+    }
+    if !no_link {
+        if let Err(e) = interp.link() {
+            return program_error(&e.to_string(), no_exit);
+        }
+    }
+    if strict {
+        let problems = strict_problems(&interp);
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        if !problems.is_empty() {
+            exit(1);
+        }
+    }
+    if fold {
+        interp.fold_constants();
+    }
+    if let Some(label) = entry {
+        if let Err(e) = interp.set_entry(&label) {
+            return program_error(&e.to_string(), no_exit);
+        }
+    }
+    interp.set_debug(debug);
+    interp.set_max_call_depth(max_call_depth);
+    interp.set_max_steps(max_steps);
+    if let Some(seed) = seed {
+        interp.set_seed(seed);
+    }
+    if let Some(path) = input_file {
+        match File::open(&path) {
+            Ok(fh) => interp.set_input(Box::new(BufReader::new(fh))),
+            Err(_) => fatal(&format!("Failed to open input file: {}", path)),
+        }
+    } else if step {
+        // stdin is reserved for debugger commands.
+        interp.set_input(Box::new(io::empty()));
+    }
+    if let Some(path) = trace_file {
+        match File::create(&path) {
+            // Line buffered so that the trace survives a fatal error.
+            Ok(fh) => {
+                interp.set_trace(Box::new(LineWriter::new(fh)));
+                interp.set_trace_jumps_only(trace_jumps);
+            }
+            Err(_) => fatal(&format!("Failed to create trace file: {}", path)),
+        }
+    }
+    if let Some(runs) = bench {
+        run_bench(&mut interp, &prog_args, runs);
+        return ExitCode::SUCCESS;
+    }
+    for val in prog_args {
+        interp.push(StackVal::Number(val));
+    }
+    let result = if step { run_stepping(&mut interp) } else { interp.run_to_result() };
+    if stats {
+        let stats = interp.stats();
+        eprintln!("{} instructions, {} label lookups, max stack depth {}",
+                  stats.instructions, stats.label_lookups, stats.max_depth);
+        let depths: Vec<String> =
+            stats.depths.iter().enumerate().map(|(d, n)| format!("{}:{}", d, n)).collect();
+        eprintln!("stack depths (depth:instructions): {}", depths.join(" "));
+    }
+    match result {
+        Ok(Outcome::Finished) => ExitCode::SUCCESS,
+        Ok(Outcome::Halt { code }) => ExitCode::from(code as u8),
+        Err(ref e) if e.is_limit() => {
+            eprintln!("{}", highlight(&format!("error: {}", e)));
+            ExitCode::from(limit_exit_code)
+        }
+        Err(e) => program_error(&e.to_string(), no_exit),
+    }
+}
+
+// Report an error in the program. With --no-exit, main returns the exit code; otherwise the
+// process exits here, as `fatal` does.
+fn program_error(msg: &str, no_exit: bool) -> ExitCode {
+    if !no_exit {
+        fatal(msg);
+    }
+    eprintln!("{}", highlight(&format!("error: {}", msg)));
+    ExitCode::FAILURE
+}
+
+// Run the program `runs` times, resetting the interpreter in between, and report the timings on
+// stderr. The program's exit code, if any, is ignored.
+fn run_bench(interp: &mut Interp, prog_args: &[RawNumber], runs: usize) {
+    let mut times: Vec<Duration> = Vec::with_capacity(runs);
+    for _ in 0..runs {
+        interp.reset();
+        for val in prog_args {
+            interp.push(StackVal::Number(*val));
+        }
+        let start = Instant::now();
         interp.run();
+        times.push(start.elapsed());
+    }
+    times.sort();
+    eprintln!("{} runs: min {:?}, median {:?}, max {:?}",
+              runs, times[0], times[runs / 2], times[runs - 1]);
+}
+
+// Run the program one instruction at a time, showing the next instruction and the stack before
+// each step. Debugger commands are read from stdin.
+fn run_stepping(interp: &mut Interp) -> Result<Outcome, RuntimeError> {
+    let stdin = io::stdin();
+    let mut commands = stdin.lock().lines();
+    let mut stepping = true;
+    loop {
+        if stepping {
+            if let Some(instr) = interp.next_instr() {
+                let stack: Vec<String> = interp.stack().iter().map(|v| v.to_string()).collect();
+                let instr = instr.display(interp.labels());
+                eprint!("{}: {}  [{}] > ", interp.pc(), instr, stack.join(" "));
+            }
+            match commands.next() {
+                Some(Ok(ref cmd)) if cmd.trim() == "" => (),
+                Some(Ok(ref cmd)) if cmd.trim() == "c" => stepping = false,
+                Some(Ok(ref cmd)) if cmd.trim() == "q" => exit(0),
+                Some(Ok(_)) => {
+                    eprintln!("commands: <enter> to step, c to continue, q to quit");
+                    continue;
+                }
+                _ => exit(0), // end of input or an error reading it.
+            }
+        }
+        if let Some(outcome) = interp.step()? {
+            return Ok(outcome);
+        }
+    }
+}
+
+// Problems which are only errors in --strict mode.
+fn strict_problems(interp: &Interp) -> Vec<String> {
+    let labels = interp.find_fall_through_labels();
+    labels
+        .iter()
+        .map(|&(addr, name)| {
+            format!("instruction {}: label '{}' can be reached by falling through", addr, name)
+        })
+        .collect()
+}
+
+// Report static problems with the program in `filename` without running it. Every parse error is
+// reported, not just the first. Returns the process exit code.
+fn run_check(filename: &str, comment_prefix: &str, strict: bool, safe: bool) -> i32 {
+    let reader = open_program(filename);
+    let interp = match Interp::from_reader_recovering(reader, comment_prefix, safe) {
+        Ok(interp) => interp,
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("error: {}", e);
+            }
+            return 1;
+        }
+    };
+    let mut problems = interp.verify();
+    if strict {
+        problems.extend(strict_problems(&interp));
+    }
+    for problem in &problems {
+        eprintln!("error: {}", problem);
+    }
+    let unreachable = interp.find_unreachable();
+    for addr in &unreachable {
+        eprintln!("error: instruction {}: unreachable", addr);
+    }
+    if problems.is_empty() && unreachable.is_empty() { 0 } else { 1 }
+}
+
+// A reader for the program in `filename`, or stdin if it is `-`.
+fn open_program(filename: &str) -> Box<dyn BufRead> {
+    if filename == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        match File::open(filename) {
+            Ok(fh) => Box::new(BufReader::new(fh)),
+            Err(_) => fatal(&format!("Failed to open input file: {}", filename)),
+        }
     }
 }